@@ -0,0 +1,236 @@
+use std::{
+    env, fmt,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cloudformatious::StackFailure;
+
+const NO_REASON: &str = "No reason";
+
+/// Whether or not to emit CI-specific output.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum CiArg {
+    /// Detect the CI environment from environment variables.
+    #[default]
+    Auto,
+
+    /// Emit GitHub Actions workflow commands.
+    Github,
+
+    /// Emit GitLab CI collapsible sections.
+    Gitlab,
+
+    /// Don't emit any CI-specific output.
+    None,
+}
+
+/// A supported CI environment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Ci {
+    GithubActions,
+    Gitlab,
+}
+
+impl Ci {
+    /// Determine the CI environment based on `--ci` and, if that's `auto`, the environment.
+    pub fn detect(arg: CiArg) -> Option<Self> {
+        match arg {
+            CiArg::Auto => {
+                if env_is_true("GITHUB_ACTIONS") {
+                    Some(Self::GithubActions)
+                } else if env_is_true("GITLAB_CI") {
+                    Some(Self::Gitlab)
+                } else {
+                    None
+                }
+            }
+            CiArg::Github => Some(Self::GithubActions),
+            CiArg::Gitlab => Some(Self::Gitlab),
+            CiArg::None => None,
+        }
+    }
+
+    /// Start a collapsible group of output with the given title.
+    ///
+    /// The returned [`Group`] should be ended with [`Group::end`].
+    pub fn start_group(self, title: &str) -> Group {
+        static SECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+        let section = format!(
+            "cloudformatious_{}",
+            SECTIONS.fetch_add(1, Ordering::Relaxed)
+        );
+        match self {
+            Self::GithubActions => eprintln!("::group::{}", escape_data(title)),
+            Self::Gitlab => eprintln!(
+                "\x1b[0Ksection_start:{}:{section}[collapsed=true]\r\x1b[0K{title}",
+                unix_timestamp()
+            ),
+        }
+        Group { ci: self, section }
+    }
+
+    /// Emit an annotation for each failed resource in `failure`.
+    ///
+    /// If `template_path` is given, it is searched for the logical ID of each resource so that the
+    /// annotation can point to the relevant line.
+    pub fn annotate_failure(self, failure: &StackFailure, template_path: Option<&Path>) {
+        let template = template_path.and_then(|path| {
+            let content = std::fs::read_to_string(path).ok()?;
+            Some((path, content))
+        });
+
+        if failure.resource_events.is_empty() {
+            self.annotate(&Annotation {
+                title: format!("Stack {} failed", failure.stack_id),
+                message: &failure.stack_status_reason,
+                location: template
+                    .as_ref()
+                    .map(|(path, _)| Location { path, line: None }),
+            });
+            return;
+        }
+
+        for (resource_status, event_details) in &failure.resource_events {
+            let resource = event_details.logical_resource_id();
+            let line = template
+                .as_ref()
+                .and_then(|(_, content)| find_resource_line(content, resource));
+            self.annotate(&Annotation {
+                title: format!(
+                    "{resource} ({}) {resource_status}",
+                    event_details.resource_type()
+                ),
+                message: event_details
+                    .resource_status_reason()
+                    .inner()
+                    .unwrap_or(NO_REASON),
+                location: template.as_ref().map(|(path, _)| Location { path, line }),
+            });
+        }
+    }
+
+    fn annotate(self, annotation: &Annotation) {
+        match self {
+            Self::GithubActions => {
+                let mut properties = String::new();
+                if let Some(location) = &annotation.location {
+                    properties.push_str(&format!(
+                        "file={},",
+                        escape_property(&location.path.to_string_lossy())
+                    ));
+                    if let Some(line) = location.line {
+                        properties.push_str(&format!("line={line},"));
+                    }
+                }
+                properties.push_str(&format!("title={}", escape_property(&annotation.title)));
+                eprintln!("::error {properties}::{}", escape_data(annotation.message));
+            }
+            Self::Gitlab => {
+                let group = self.start_group(&annotation.title);
+                if let Some(location) = &annotation.location {
+                    eprintln!("{location}");
+                }
+                eprintln!("{}", annotation.message);
+                group.end();
+            }
+        }
+    }
+}
+
+/// A collapsible group of output started by [`Ci::start_group`].
+#[must_use]
+pub struct Group {
+    ci: Ci,
+    section: String,
+}
+
+impl Group {
+    pub fn end(self) {
+        match self.ci {
+            Ci::GithubActions => eprintln!("::endgroup::"),
+            Ci::Gitlab => eprintln!(
+                "\x1b[0Ksection_end:{}:{}\r\x1b[0K",
+                unix_timestamp(),
+                self.section
+            ),
+        }
+    }
+}
+
+struct Annotation<'a> {
+    title: String,
+    message: &'a str,
+    location: Option<Location<'a>>,
+}
+
+struct Location<'a> {
+    path: &'a Path,
+    line: Option<usize>,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        Ok(())
+    }
+}
+
+fn env_is_true(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| value.eq_ignore_ascii_case("true"))
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Find the (1-based) line on which `logical_id` is declared in the `Resources` of a template.
+///
+/// This is a textual search, rather than a YAML parse, since parsed templates don't retain
+/// position information.
+fn find_resource_line(template: &str, logical_id: &str) -> Option<usize> {
+    let yaml_key = format!("{logical_id}:");
+    let json_key = format!("\"{logical_id}\"");
+    template
+        .lines()
+        .enumerate()
+        .skip_while(|(_, line)| {
+            let line = line.trim_start_matches(['"', '{', ' ']);
+            !line.starts_with("Resources")
+        })
+        .find(|(_, line)| {
+            let line = line.trim_start();
+            line.starts_with(&yaml_key) || line.starts_with(&json_key)
+        })
+        .map(|(index, _)| index + 1)
+}
+
+fn escape_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(property: &str) -> String {
+    escape_data(property)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
+#[test]
+fn test_find_resource_line() {
+    let yaml = "Parameters:\n  Bucket:\n    Type: String\nResources:\n  Queue:\n    Type: AWS::SQS::Queue\n  Bucket:\n    Type: AWS::S3::Bucket\n";
+    assert_eq!(find_resource_line(yaml, "Queue"), Some(5));
+    assert_eq!(find_resource_line(yaml, "Bucket"), Some(7));
+    assert_eq!(find_resource_line(yaml, "Topic"), None);
+
+    let json = "{\n  \"Resources\": {\n    \"Queue\": {\n      \"Type\": \"AWS::SQS::Queue\"\n    }\n  }\n}\n";
+    assert_eq!(find_resource_line(json, "Queue"), Some(3));
+}
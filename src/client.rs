@@ -1,6 +1,9 @@
 use std::fmt;
 
-use aws_config::{meta::credentials::CredentialsProviderChain, SdkConfig};
+use aws_config::{
+    meta::{credentials::CredentialsProviderChain, region::RegionProviderChain},
+    SdkConfig,
+};
 use aws_sdk_s3::config::ProvideCredentials;
 use aws_types::region::Region;
 
@@ -35,13 +38,14 @@ pub async fn get_config(region: Option<Region>, no_input: bool) -> Result<SdkCon
     Ok(config)
 }
 
-pub async fn get_client<C>(
-    ctor: impl FnOnce(&SdkConfig) -> C,
-    region: Option<Region>,
-    no_input: bool,
-) -> Result<C, Error> {
-    let config = get_config(region, no_input).await?;
-    Ok(ctor(&config))
+/// Resolve the region from `region`, or the environment and profile, as `get_config` would.
+///
+/// Unlike `get_config`, this doesn't load credentials, so it works offline.
+pub async fn get_region(region: Option<Region>) -> Option<Region> {
+    RegionProviderChain::first_try(region)
+        .or_default_provider()
+        .region()
+        .await
 }
 
 #[derive(Debug)]
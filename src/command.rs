@@ -1,22 +1,81 @@
 mod apply_stack;
 mod completions;
 mod delete_stack;
+mod gc_packages;
+mod package;
+mod package_args;
+mod upload_packages;
+
+use std::path::Path;
 
 use aws_types::region::Region;
+use cloudformatious::StackEvent;
+use futures_util::{future, Stream, StreamExt};
 
-use crate::Error;
+use crate::{
+    fmt::{print_events, Output, Sizing},
+    junit, Error,
+};
 
 #[derive(Debug, clap::Parser)]
 pub enum Command {
     Completions(self::completions::Args),
-    ApplyStack(self::apply_stack::Args),
+    ApplyStack(Box<self::apply_stack::Args>),
     DeleteStack(self::delete_stack::Args),
+    GcPackages(self::gc_packages::Args),
+    Package(Box<self::package::Args>),
+    UploadPackages(self::upload_packages::Args),
 }
 
-pub async fn main(region: Option<Region>, command: Command) -> Result<(), Error> {
+impl Command {
+    /// The path to the template file the command operates on, if any.
+    pub fn template_path(&self) -> Option<&Path> {
+        match self {
+            Self::ApplyStack(args) => args.template_path(),
+            Self::Package(args) => args.template_path(),
+            Self::Completions(_)
+            | Self::DeleteStack(_)
+            | Self::GcPackages(_)
+            | Self::UploadPackages(_) => None,
+        }
+    }
+}
+
+pub async fn main(region: Option<Region>, output: Output, command: Command) -> Result<(), Error> {
     match command {
         Command::Completions(args) => self::completions::main(args),
-        Command::ApplyStack(args) => self::apply_stack::main(region, args).await,
-        Command::DeleteStack(args) => self::delete_stack::main(region, args).await,
+        Command::ApplyStack(args) => self::apply_stack::main(region, output, *args).await,
+        Command::DeleteStack(args) => self::delete_stack::main(region, output, args).await,
+        Command::GcPackages(args) => self::gc_packages::main(region, args).await,
+        Command::Package(args) => self::package::main(region, output, *args).await,
+        Command::UploadPackages(args) => self::upload_packages::main(region, output, args).await,
+    }
+}
+
+/// Drive a stack operation's `events` to completion.
+///
+/// Events are printed unless `quiet` is set, and recorded in `report` if given. If there's nothing
+/// to do, `events` is not polled at all.
+async fn watch_events(
+    output: Output,
+    quiet: bool,
+    title: &str,
+    sizing: &Sizing,
+    mut report: Option<&mut junit::Report>,
+    events: impl Stream<Item = StackEvent> + Unpin,
+) {
+    if quiet && report.is_none() {
+        return;
+    }
+
+    let events = events.inspect(|event| {
+        if let Some(report) = report.as_deref_mut() {
+            report.record(event);
+        }
+    });
+    if quiet {
+        events.for_each(|_| future::ready(())).await;
+    } else {
+        print_events(output, title, sizing, events).await;
     }
 }
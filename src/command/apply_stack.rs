@@ -1,16 +1,24 @@
-use std::{collections::HashMap, convert::TryInto, fmt, path::PathBuf, str::FromStr};
+use std::{
+    convert::TryInto,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use aws_types::region::Region;
 use cloudformatious::{
     self, ApplyStackError, ApplyStackInput, BlockedStackStatus, Capability, Client,
-    DeleteStackError, DeleteStackInput, Parameter, Tag, TemplateSource,
+    DeleteStackError, DeleteStackInput, Parameter, TemplateSource,
 };
-use futures_util::TryFutureExt;
 
+use super::{
+    package_args::{PackageArgs, PackageUploadArgs, TagArg},
+    watch_events,
+};
 use crate::{
     client::get_config,
-    fmt::{print_events, Sizing},
-    package, s3, Error, Template,
+    fmt::{Output, Sizing},
+    junit, Error, Template,
 };
 
 /// Apply a CloudFormation template.
@@ -22,14 +30,63 @@ use crate::{
 ///
 /// The template will be pre-processed for references to local paths in the following properties:
 ///
-/// - `AWS::Lambda::Function`: `Code`
+/// - `AWS::ApiGateway::RestApi`: `BodyS3Location`
+/// - `AWS::AppSync::GraphQLSchema`: `DefinitionS3Location`
+/// - `AWS::AppSync::Resolver`: `RequestMappingTemplateS3Location`,
+///   `ResponseMappingTemplateS3Location`
+/// - `AWS::CloudFormation::Stack`: `TemplateURL` (the template is packaged recursively)
+/// - `AWS::CloudFormation::StackSet`: `TemplateURL` (the template is packaged recursively)
+/// - `AWS::Glue::Job`: `Command.ScriptLocation`
+/// - `AWS::Lambda::Function`: `Code`, `Code.ImageUri` (the image is built and pushed to
+///   `--image-repository`)
+/// - `AWS::Lambda::LayerVersion`: `Content`
+/// - `AWS::Serverless::Api`: `DefinitionUri`
+/// - `AWS::Serverless::Application`: `Location` (the template is packaged recursively)
+/// - `AWS::Serverless::Function`: `CodeUri`
+/// - `AWS::Serverless::LayerVersion`: `ContentUri`
+/// - `AWS::Serverless::StateMachine`: `DefinitionUri`
+/// - `AWS::StepFunctions::StateMachine`: `DefinitionS3Location`
+///
+/// Local `Location`s of `AWS::Include` transforms anywhere in the template are also uploaded, and
+/// replaced with `s3://` URIs, unless `--inline-includes` is given.
+///
+/// Lambda code and layer content are zipped, and other files are uploaded as-is. Values that are
+/// already `s3://`, `http://`, or `https://` URIs are left alone.
+///
+/// Resources can declare a build step to run before packaging in their `Metadata`, e.g.:
 ///
-/// If local paths are found, they will be zipped and uploaded to S3 based on `--package-bucket`
-/// and `--package-prefix`. `--package-bucket` is required if the template contains any local paths.
+/// ```yaml
+/// Metadata:
+///   Cloudformatious:
+///     Build: npm ci && npm run build
+///     Output: dist
+/// ```
+///
+/// The `Build` command is run with a shell in the packaged directory, and `Output` (if given) is
+/// packaged instead of the directory itself. Build output is printed to STDERR, unless disabled
+/// with `--quiet`.
+///
+/// Functions using an OS-only runtime (`provided.al2` or `provided.al2023`) can instead set
+/// `Builder: rust` or `Builder: go` to compile the Cargo package or Go module at the packaged path
+/// for the function's `Architectures`. The binary is zipped as `bootstrap`. For Cargo packages with
+/// several binaries, `Binary` selects which one to use.
+///
+/// If local paths are found, they will be uploaded to S3 based on `--package-bucket`
+/// and `--package-prefix`. `--package-bucket` is required if the template contains any local paths
+/// other than container images.
+/// Up to `--package-concurrency` packages are processed at once, and the progress of each upload
+/// (or whether it was skipped because the content is already present) is printed to STDERR,
+/// unless disabled with `--quiet`.
+///
+/// Uploaded packages are recorded in a cache under `$XDG_CACHE_HOME/cloudformatious`, keyed by the
+/// paths, sizes, and modification times of their source files. Packages whose sources haven't
+/// changed since a previous upload are not zipped or hashed again, as long as the previously
+/// uploaded object still exists.
 ///
 /// # Output
 ///
 /// Stack events are printed to STDERR as the operation proceeds, unless disabled with `--quiet`.
+/// The format of events can be changed with the global `--output-format` option.
 ///
 /// If the stack operation succeeds and there are no resource errors, then the stack's outputs
 /// are printed to STDOUT as JSON.
@@ -59,6 +116,16 @@ pub struct Args {
     #[clap(long)]
     client_request_token: Option<String>,
 
+    /// Write a JUnit XML report of resource results to the given path.
+    ///
+    /// The report contains a test case for each resource in the change set, which fails if the
+    /// resource entered a `*_FAILED` state during the operation. If the operation fails before
+    /// resource events are available (e.g. the template could not be packaged, or the change set
+    /// could not be created), the report contains a single failed test case for the stack with the
+    /// error.
+    #[clap(long, value_name = "PATH")]
+    junit_report: Option<PathBuf>,
+
     /// A flag to indicate that no input can be obtained.
     ///
     /// For example, this will cause the operation to fail if SSO authentication is configured and
@@ -70,16 +137,6 @@ pub struct Args {
     #[clap(long, num_args(1..))]
     notification_arns: Vec<String>,
 
-    /// The S3 bucket to upload packages to.
-    ///
-    /// Not required unless there are references to local paths in the template.
-    #[clap(long)]
-    package_bucket: Option<String>,
-
-    /// A prefix for any uploaded packages.
-    #[clap(long)]
-    package_prefix: Option<String>,
-
     /// A list of input parameters for the stack.
     #[clap(long, num_args(1..), value_name("KEY=VALUE"))]
     parameters: Vec<ParameterArg>,
@@ -112,9 +169,20 @@ pub struct Args {
     /// Path to the template to be applied.
     #[clap(long)]
     template_path: PathBuf,
+
+    #[clap(flatten)]
+    package: PackageArgs,
+
+    #[clap(flatten)]
+    package_upload: PackageUploadArgs,
 }
 
 impl Args {
+    /// The path to the template, unless it's read from STDIN.
+    pub fn template_path(&self) -> Option<&Path> {
+        Some(self.template_path.as_path()).filter(|path| *path != Path::new("-"))
+    }
+
     fn into_input(self, template: &Template) -> ApplyStackInput {
         ApplyStackInput {
             capabilities: self.capabilities.into_iter().map(Into::into).collect(),
@@ -135,13 +203,38 @@ impl Args {
     }
 }
 
-pub async fn main(region: Option<Region>, args: Args) -> Result<(), Error> {
+pub async fn main(region: Option<Region>, output: Output, args: Args) -> Result<(), Error> {
     let quiet = args.quiet;
+    let title = format!("Applying stack {}", args.stack_name);
+    let junit_report = args.junit_report.clone();
 
-    let mut template = Template::open(args.template_path.clone()).await?;
-    preprocess(region.as_ref(), &args, &mut template).await?;
-
-    let config = get_config(region, args.no_input).await?;
+    let prepared = async {
+        let mut template = Template::open(args.template_path.clone()).await?;
+        if args.package.inline_includes {
+            template.inline_includes().await?;
+        }
+        args.package
+            .process(
+                &args.package_upload,
+                region.as_ref(),
+                args.no_input,
+                args.quiet,
+                output.format,
+                &mut template,
+            )
+            .await?;
+
+        let config = get_config(region, args.no_input).await?;
+        Ok::<_, Error>((template, config))
+    }
+    .await;
+    let (template, config) = match prepared {
+        Ok(prepared) => prepared,
+        Err(error) => {
+            let report = junit::Report::new(&args.stack_name);
+            return Err(write_error_report(junit_report.as_deref(), report, error).await);
+        }
+    };
     let client = cloudformatious::Client::new(&config);
     let input = args.into_input(&template);
     let mut apply = client.apply_stack(input.clone());
@@ -149,40 +242,69 @@ pub async fn main(region: Option<Region>, args: Args) -> Result<(), Error> {
     let change_set = match apply.change_set().await {
         Ok(change_set) => Ok(change_set),
         Err(ApplyStackError::Blocked { status }) => {
-            recover(status, &client, &input, quiet).await?;
-
-            apply = client.apply_stack(input.clone());
-            apply.change_set().await.map_err(Error::other)
+            match recover(status, &client, &input, output, quiet).await {
+                Ok(()) => {
+                    apply = client.apply_stack(input.clone());
+                    apply.change_set().await.map_err(Error::other)
+                }
+                Err(error) => Err(error),
+            }
         }
         Err(error) => Err(Error::other(error)),
-    }?;
+    };
+    let change_set = match change_set {
+        Ok(change_set) => change_set,
+        Err(error) => {
+            let report = junit::Report::new(&input.stack_name);
+            return Err(write_error_report(junit_report.as_deref(), report, error).await);
+        }
+    };
     let sizing = Sizing::new_for_change_set(&change_set);
+    let mut report = junit_report
+        .as_ref()
+        .map(|_| junit::Report::for_change_set(&change_set));
+
+    watch_events(
+        output,
+        quiet,
+        &title,
+        &sizing,
+        report.as_mut(),
+        apply.events(),
+    )
+    .await;
 
-    if !quiet {
-        print_events(&sizing, apply.events()).await;
-    }
-
-    let output = apply
-        .or_else(|error| async {
-            let ApplyStackError::Blocked { status } = error else {
-                return Err(Error::other(error));
-            };
-
-            recover(status, &client, &input, quiet).await?;
+    let result = match apply.await {
+        Err(ApplyStackError::Blocked { status }) => {
+            if let Err(error) = recover(status, &client, &input, output, quiet).await {
+                let report = report.unwrap_or_else(|| junit::Report::new(&input.stack_name));
+                return Err(write_error_report(junit_report.as_deref(), report, error).await);
+            }
 
             let mut apply = client.apply_stack(input);
+            watch_events(
+                output,
+                quiet,
+                &title,
+                &sizing,
+                report.as_mut(),
+                apply.events(),
+            )
+            .await;
+            apply.await
+        }
+        result => result,
+    };
 
-            if !quiet {
-                print_events(&sizing, apply.events()).await;
-            }
+    if let (Some(path), Some(report)) = (&junit_report, &report) {
+        report.write(path).await?;
+    }
 
-            apply.await.map_err(|error| match error {
-                ApplyStackError::Warning { warning, .. } => Error::Warning(warning),
-                ApplyStackError::Failure(failure) => Error::Failure(failure),
-                error => Error::other(error),
-            })
-        })
-        .await?;
+    let output = result.map_err(|error| match error {
+        ApplyStackError::Warning { warning, .. } => Error::Warning(warning),
+        ApplyStackError::Failure(failure) => Error::Failure(failure),
+        error => Error::other(error),
+    })?;
 
     let outputs_json: serde_json::Value = output
         .outputs
@@ -198,47 +320,26 @@ pub async fn main(region: Option<Region>, args: Args) -> Result<(), Error> {
     Ok(())
 }
 
-async fn preprocess(
-    region: Option<&Region>,
-    args: &Args,
-    template: &mut Template,
-) -> Result<(), Error> {
-    let mut targets = package::targets(template).peekable();
-    if targets.peek().is_none() {
-        return Ok(());
-    }
-
-    let package_bucket = if let Some(bucket) = args.package_bucket.as_deref() {
-        bucket
-    } else {
-        drop(targets); // it's not clear why this is necessary, but without it the use of `template`
-                       // below is an error
-        return Err(Error::other(format!(
-            concat!(
-                "the `--package-bucket` option is required because template `{}` contains ",
-                "references to local paths that will be packaged"
-            ),
-            template.source()
-        )));
+/// Write a JUnit report to `path` (if given), recording `error` as a failed test case.
+///
+/// This is used when the operation fails before its result is known. The returned error is
+/// `error`, unless writing the report fails.
+async fn write_error_report(path: Option<&Path>, mut report: junit::Report, error: Error) -> Error {
+    let Some(path) = path else {
+        return error;
     };
-
-    let client = s3::Client::new(region.cloned(), args.no_input).await?;
-
-    package::process(
-        &client,
-        package_bucket,
-        args.package_prefix.as_deref(),
-        targets,
-    )
-    .await?;
-
-    Ok(())
+    report.record_error(&error);
+    match report.write(path).await {
+        Ok(()) => error,
+        Err(write_error) => write_error,
+    }
 }
 
 async fn recover(
     status: BlockedStackStatus,
     client: &Client,
     input: &ApplyStackInput,
+    output: Output,
     quiet: bool,
 ) -> Result<(), Error> {
     match status {
@@ -251,9 +352,15 @@ async fn recover(
             let mut delete = client.delete_stack(delete_input);
             let sizing = Sizing::default();
 
-            if !quiet {
-                print_events(&sizing, delete.events()).await;
-            }
+            watch_events(
+                output,
+                quiet,
+                &format!("Deleting stack {}", input.stack_name),
+                &sizing,
+                None,
+                delete.events(),
+            )
+            .await;
 
             delete.await.map_err(|error| match error {
                 DeleteStackError::Warning(warning) => Error::Warning(warning),
@@ -350,58 +457,3 @@ impl fmt::Display for InvalidParameter {
 }
 
 impl std::error::Error for InvalidParameter {}
-
-/// Newtype for parsing tags.
-#[derive(Clone, Debug)]
-pub enum TagArg {
-    KeyValue(Tag),
-    Json(Vec<Tag>),
-}
-
-impl FromStr for TagArg {
-    type Err = InvalidTag;
-    fn from_str(tag: &str) -> Result<Self, Self::Err> {
-        // First try to parse as JSON
-        let tags: Result<HashMap<String, String>, _> = serde_json::from_str(tag);
-        if let Ok(tags) = tags {
-            return Ok(TagArg::Json(
-                tags.into_iter()
-                    .map(|(key, value)| Tag { key, value })
-                    .collect(),
-            ));
-        }
-
-        let kv: Vec<_> = tag.splitn(2, '=').collect();
-        let [key, value]: [_; 2] = kv.try_into().map_err(|_| InvalidTag(tag.to_string()))?;
-        Ok(Self::KeyValue(Tag {
-            key: key.to_string(),
-            value: value.to_string(),
-        }))
-    }
-}
-
-impl IntoIterator for TagArg {
-    type Item = Tag;
-    type IntoIter = std::vec::IntoIter<Self::Item>;
-    fn into_iter(self) -> Self::IntoIter {
-        match self {
-            Self::KeyValue(tag) => vec![tag].into_iter(),
-            Self::Json(tags) => tags.into_iter(),
-        }
-    }
-}
-
-#[derive(Debug)]
-pub struct InvalidTag(String);
-
-impl fmt::Display for InvalidTag {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "invalid tag `{}`, must be in the form `key=value` or a JSON object",
-            self.0
-        )
-    }
-}
-
-impl std::error::Error for InvalidTag {}
@@ -1,12 +1,16 @@
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    path::PathBuf,
+};
 
 use aws_types::region::Region;
 use cloudformatious::{self, DeleteStackError, DeleteStackInput};
 
+use super::watch_events;
 use crate::{
     client::get_config,
-    fmt::{print_events, Sizing},
-    Error,
+    fmt::{Output, Sizing},
+    junit, Error,
 };
 
 /// Delete a CloudFormation stack.
@@ -14,6 +18,7 @@ use crate::{
 /// # Output
 ///
 /// Stack events are printed to STDERR as the operation proceeds, unless disable with `--quiet`.
+/// The format of events can be changed with the global `--output-format` option.
 ///
 /// If the stack is deleted successfully and there are no resource errors, or if no stack
 /// existed in the first place, a confirmation message is printed to STDERR.
@@ -41,6 +46,13 @@ pub struct Args {
     #[clap(long)]
     client_request_token: Option<String>,
 
+    /// Write a JUnit XML report of resource results to the given path.
+    ///
+    /// The report contains a test case for each resource that received an event, which fails if
+    /// the resource entered a `*_FAILED` state during the deletion.
+    #[clap(long, value_name = "PATH")]
+    junit_report: Option<PathBuf>,
+
     /// A flag to indicate that no input can be obtained.
     ///
     /// For example, this will cause the operation to fail if SSO authentication is configured and
@@ -84,19 +96,36 @@ impl TryFrom<Args> for DeleteStackInput {
     }
 }
 
-pub async fn main(region: Option<Region>, args: Args) -> Result<(), Error> {
+pub async fn main(region: Option<Region>, output: Output, args: Args) -> Result<(), Error> {
     let quiet = args.quiet;
+    let title = format!("Deleting stack {}", args.stack_name);
+    let junit_report = args.junit_report.clone();
+    let mut report = junit_report
+        .as_ref()
+        .map(|_| junit::Report::new(&args.stack_name));
 
     let config = get_config(region, args.no_input).await?;
     let client = cloudformatious::Client::new(&config);
     let mut delete = client.delete_stack(args.try_into()?);
     let sizing = Sizing::default();
 
-    if !quiet {
-        print_events(&sizing, delete.events()).await;
+    watch_events(
+        output,
+        quiet,
+        &title,
+        &sizing,
+        report.as_mut(),
+        delete.events(),
+    )
+    .await;
+
+    let result = delete.await;
+
+    if let (Some(path), Some(report)) = (&junit_report, &report) {
+        report.write(path).await?;
     }
 
-    delete.await.map_err(|error| match error {
+    result.map_err(|error| match error {
         DeleteStackError::Warning(warning) => Error::Warning(warning),
         DeleteStackError::Failure(failure) => Error::Failure(failure),
         DeleteStackError::CloudFormationApi(_) => Error::other(error),
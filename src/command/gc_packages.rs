@@ -0,0 +1,370 @@
+use std::{
+    collections::HashSet,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aws_sdk_cloudformation::types::TemplateStage;
+use aws_types::region::Region;
+use percent_encoding::percent_decode_str;
+use serde_yaml::Value as YamlValue;
+
+use crate::{client::get_config, s3, Error};
+
+/// Delete packages that are no longer referenced by any deployed stack.
+///
+/// Packages are uploaded with content-addressed keys, so old packages accumulate as stacks are
+/// updated. This command scans the deployed templates of the given stacks (and their nested
+/// stacks) for references to objects under `--package-prefix` in `--package-bucket`, and deletes
+/// any unreferenced objects that are older than `--older-than-days`.
+///
+/// References are recognised in the forms packaging produces: `s3://` URIs, S3 HTTPS URLs, and
+/// `S3Bucket`/`S3Key` or `Bucket`/`Key` properties. Both the original and processed templates are
+/// scanned. The command fails if any of the stacks has an operation in progress, since a rollback
+/// may still need packages that aren't in its current template.
+///
+/// Objects referenced by stacks that aren't given (directly or as nested stacks) will be deleted,
+/// so every stack that shares the bucket and prefix should be given.
+///
+/// # Output
+///
+/// The URI of each deleted object (or each object that would be deleted, with `--dry-run`) is
+/// printed to STDOUT. A summary is printed to STDERR, unless disabled with `--quiet`.
+#[derive(Debug, clap::Parser)]
+pub struct Args {
+    /// Consider every object in the bucket, rather than only those under `--package-prefix`.
+    #[clap(long, conflicts_with("package_prefix"))]
+    all: bool,
+
+    /// List unreferenced packages, without deleting them.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// A flag to indicate that no input can be obtained.
+    ///
+    /// For example, this will cause the operation to fail if SSO authentication is configured and
+    /// not refereshed.
+    #[clap(long, default_value_t)]
+    no_input: bool,
+
+    /// Only delete unreferenced packages last modified more than this many days ago.
+    ///
+    /// This avoids deleting packages that have been uploaded for a deployment that hasn't started
+    /// yet, so it must be at least 1.
+    #[clap(
+        long,
+        value_name("DAYS"),
+        default_value_t = 30,
+        value_parser(clap::value_parser!(u32).range(1..))
+    )]
+    older_than_days: u32,
+
+    /// The S3 bucket packages were uploaded to.
+    ///
+    /// If `auto`, the managed bucket for the current account and region is used.
+    #[clap(long)]
+    package_bucket: String,
+
+    /// The prefix packages were uploaded under.
+    ///
+    /// Only objects under this prefix are considered for deletion. Required unless `--all` is
+    /// given.
+    #[clap(long, required_unless_present("all"))]
+    package_prefix: Option<String>,
+
+    /// Disable informational output to STDERR.
+    #[clap(long)]
+    quiet: bool,
+
+    /// The names of the stacks whose packages should be kept.
+    #[clap(long, num_args(1..), required(true))]
+    stack_names: Vec<String>,
+}
+
+pub async fn main(region: Option<Region>, args: Args) -> Result<(), Error> {
+    let prefix = args.package_prefix.unwrap_or_default();
+    if prefix.is_empty() && !args.all {
+        return Err(Error::other(
+            "`--package-prefix` must not be empty, use `--all` to consider every object in the \
+             bucket",
+        ));
+    }
+
+    let config = get_config(region.clone(), args.no_input).await?;
+    let client = aws_sdk_cloudformation::Client::new(&config);
+    let templates = deployed_templates(&client, &args.stack_names).await?;
+
+    let s3 = s3::Client::new(region, args.no_input).await?;
+    let bucket = if args.package_bucket == s3::MANAGED_BUCKET {
+        s3.existing_managed_bucket().await?
+    } else {
+        args.package_bucket
+    };
+
+    let mut referenced = HashSet::new();
+    for template in &templates {
+        referenced_keys(template, &bucket, &mut referenced);
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64);
+    let cutoff = now - i64::from(args.older_than_days) * 24 * 60 * 60;
+
+    let prefix = list_prefix(&prefix);
+    let keys: Vec<_> = s3
+        .list_objects(&bucket, &prefix)
+        .await?
+        .into_iter()
+        .filter(|object| object.last_modified < cutoff)
+        .filter(|object| !referenced.contains(&object.key))
+        .map(|object| object.key)
+        .collect();
+
+    if !args.dry_run {
+        s3.delete_objects(&bucket, &keys).await?;
+    }
+    for key in &keys {
+        println!("s3://{bucket}/{key}");
+    }
+
+    if !args.quiet {
+        let action = if args.dry_run { "Found" } else { "Deleted" };
+        eprintln!(
+            "{action} {} unreferenced package(s) in s3://{bucket}/{prefix}",
+            keys.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Get the deployed templates of `stack_names` and all their nested stacks.
+///
+/// Both the original and processed stages of each template are returned, so references added or
+/// removed by transforms are found. It's an error for any stack to have an operation in progress.
+async fn deployed_templates(
+    client: &aws_sdk_cloudformation::Client,
+    stack_names: &[String],
+) -> Result<Vec<YamlValue>, Error> {
+    let mut stacks = stack_names.to_vec();
+    let mut templates = Vec::new();
+    while let Some(stack) = stacks.pop() {
+        let status = client
+            .describe_stacks()
+            .stack_name(&stack)
+            .send()
+            .await
+            .map_err(|error| {
+                Error::other(format!(
+                    "an error occurred when describing stack {stack}: {error}"
+                ))
+            })?
+            .stacks
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .and_then(|stack| stack.stack_status);
+        if let Some(status) = status.filter(|status| status.as_str().ends_with("_IN_PROGRESS")) {
+            return Err(Error::other(format!(
+                "stack {stack} has an operation in progress ({}), try again once it has settled",
+                status.as_str()
+            )));
+        }
+
+        for stage in [TemplateStage::Original, TemplateStage::Processed] {
+            let template = client
+                .get_template()
+                .stack_name(&stack)
+                .template_stage(stage)
+                .send()
+                .await
+                .map_err(|error| {
+                    Error::other(format!(
+                        "an error occurred when getting the template of stack {stack}: {error}"
+                    ))
+                })?;
+            let Some(body) = template.template_body else {
+                continue;
+            };
+            // JSON templates are also valid YAML
+            templates.push(serde_yaml::from_str(&body).map_err(|error| {
+                Error::other(format!(
+                    "couldn't parse the template of stack {stack}: {error}"
+                ))
+            })?);
+        }
+
+        let mut pages = client
+            .list_stack_resources()
+            .stack_name(&stack)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|error| {
+                Error::other(format!(
+                    "an error occurred when listing the resources of stack {stack}: {error}"
+                ))
+            })?;
+            stacks.extend(
+                page.stack_resource_summaries
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|resource| {
+                        resource.resource_type.as_deref() == Some("AWS::CloudFormation::Stack")
+                    })
+                    .filter_map(|resource| resource.physical_resource_id),
+            );
+        }
+    }
+    Ok(templates)
+}
+
+/// The prefix to list objects under `prefix` with.
+///
+/// S3 prefixes are plain string prefixes, so a trailing `/` is needed to exclude siblings like
+/// `pkg-staging/` or `pkg2/` when listing `pkg`.
+fn list_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{prefix}/")
+    }
+}
+
+/// Collect the keys of objects in `bucket` that are referenced by `value` into `keys`.
+fn referenced_keys(value: &YamlValue, bucket: &str, keys: &mut HashSet<String>) {
+    match value {
+        YamlValue::String(string) => keys.extend(object_key(string, bucket)),
+        YamlValue::Mapping(mapping) => {
+            for (bucket_prop, key_prop) in [("S3Bucket", "S3Key"), ("Bucket", "Key")] {
+                if mapping.get(bucket_prop).and_then(YamlValue::as_str) == Some(bucket) {
+                    if let Some(key) = mapping.get(key_prop).and_then(YamlValue::as_str) {
+                        keys.insert(key.to_string());
+                    }
+                }
+            }
+            for value in mapping.values() {
+                referenced_keys(value, bucket, keys);
+            }
+        }
+        YamlValue::Sequence(sequence) => {
+            for value in sequence {
+                referenced_keys(value, bucket, keys);
+            }
+        }
+        YamlValue::Tagged(tagged) => referenced_keys(&tagged.value, bucket, keys),
+        YamlValue::Null | YamlValue::Bool(_) | YamlValue::Number(_) => {}
+    }
+}
+
+/// The key referenced by `uri`, if it's an `s3://` URI or S3 URL for an object in `bucket`.
+///
+/// Both virtual-hosted (`https://bucket.s3.region.amazonaws.com/key`) and path-style
+/// (`https://s3.region.amazonaws.com/bucket/key`) URLs are recognised, and their keys are
+/// percent-decoded.
+fn object_key(uri: &str, bucket: &str) -> Option<String> {
+    if let Some(rest) = uri.strip_prefix("s3://") {
+        let key = rest.strip_prefix(bucket)?.strip_prefix('/')?;
+        return Some(key.to_string());
+    }
+
+    let rest = uri
+        .strip_prefix("https://")
+        .or_else(|| uri.strip_prefix("http://"))?;
+    let (host, path) = rest.split_once('/')?;
+    let path = path.split(['?', '#']).next()?;
+    let key = match host
+        .strip_prefix(bucket)
+        .and_then(|host| host.strip_prefix('.'))
+    {
+        Some(domain) if is_s3_domain(domain) => path,
+        _ if is_s3_domain(host) => path.strip_prefix(bucket)?.strip_prefix('/')?,
+        _ => return None,
+    };
+    let key = percent_decode_str(key).decode_utf8().ok()?;
+    Some(key.into_owned())
+}
+
+/// Whether `host` is an S3 endpoint, e.g. `s3.amazonaws.com` or `s3.eu-west-1.amazonaws.com`.
+fn is_s3_domain(host: &str) -> bool {
+    (host.starts_with("s3.") || host.starts_with("s3-"))
+        && (host.ends_with(".amazonaws.com") || host.ends_with(".amazonaws.com.cn"))
+}
+
+#[test]
+fn test_list_prefix() {
+    assert_eq!(list_prefix(""), "");
+    assert_eq!(list_prefix("pkg"), "pkg/");
+    assert_eq!(list_prefix("pkg/"), "pkg/");
+    assert_eq!(list_prefix("a/pkg"), "a/pkg/");
+
+    let prefix = list_prefix("pkg");
+    for key in ["pkg-staging/abc.zip", "pkg2/abc.zip", "pkg.zip"] {
+        assert!(!key.starts_with(&prefix), "{}", key);
+    }
+    assert!("pkg/Function/abc.zip".starts_with(&prefix));
+}
+
+#[test]
+fn test_object_key() {
+    for uri in [
+        "s3://bucket/pkg/Function A/abc.zip",
+        "https://bucket.s3.amazonaws.com/pkg/Function%20A/abc.zip",
+        "https://bucket.s3.eu-west-1.amazonaws.com/pkg/Function%20A/abc.zip",
+        "https://s3.eu-west-1.amazonaws.com/bucket/pkg/Function%20A/abc.zip",
+        "https://s3-eu-west-1.amazonaws.com/bucket/pkg/Function%20A/abc.zip?versionId=1",
+    ] {
+        assert_eq!(
+            object_key(uri, "bucket").as_deref(),
+            Some("pkg/Function A/abc.zip"),
+            "{uri}"
+        );
+    }
+
+    for uri in [
+        "s3://bucket-2/pkg/abc.zip",
+        "https://bucket-2.s3.amazonaws.com/pkg/abc.zip",
+        "https://s3.amazonaws.com/bucket-2/pkg/abc.zip",
+        "https://bucket.example.com/pkg/abc.zip",
+        "pkg/abc.zip",
+    ] {
+        assert_eq!(object_key(uri, "bucket"), None, "{uri}");
+    }
+}
+
+#[test]
+fn test_referenced_keys() {
+    let template: YamlValue = serde_yaml::from_str(
+        r#"
+        Resources:
+          Function:
+            Type: AWS::Lambda::Function
+            Properties:
+              Code:
+                S3Bucket: bucket
+                S3Key: foo/abcd
+          Api:
+            Type: AWS::Serverless::Api
+            Properties:
+              DefinitionUri:
+                Bucket: other-bucket
+                Key: foo/other
+          Stack:
+            Type: AWS::CloudFormation::Stack
+            Properties:
+              TemplateURL: !Sub https://bucket.s3.amazonaws.com/foo/nested%2B1.yaml
+        "#,
+    )
+    .unwrap();
+
+    let mut keys = HashSet::new();
+    referenced_keys(&template, "bucket", &mut keys);
+    assert_eq!(
+        keys,
+        HashSet::from(["foo/abcd".to_string(), "foo/nested+1.yaml".to_string()])
+    );
+
+    // Keys must match exactly, not just as a prefix or substring
+    assert!(!keys.contains("foo/abc"));
+}
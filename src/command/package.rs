@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+use aws_types::region::Region;
+
+use super::package_args::PackageArgs;
+use crate::{client::get_region, fmt::Output, Error, Template};
+
+/// Package a CloudFormation template into a local directory.
+///
+/// Local paths referenced by the template are packaged as for `apply-stack`, but packages are
+/// written to `--package-dir` rather than uploaded to S3. Packages are named by the keys they
+/// would be uploaded to, so they can be inspected or tested offline, and later uploaded with
+/// `upload-packages`. Upload settings, such as `--package-sse` or `--package-tags`, are given to
+/// `upload-packages` rather than here.
+///
+/// Container images can't be packaged to a local directory, since they're pushed to a registry
+/// rather than written as files, so it's an error for the template to reference a local image.
+///
+/// A `manifest.json` is also written to `--package-dir`, recording the bucket and region, and the
+/// resource ID, property, source, and key of each package.
+///
+/// # Output
+///
+/// The packaged template, which references packages at their eventual location in
+/// `--package-bucket`, is printed to STDOUT. Progress is printed to STDERR, unless disabled with
+/// `--quiet`.
+#[derive(Debug, clap::Parser)]
+pub struct Args {
+    /// The directory to write packages and the manifest to.
+    #[clap(long, value_name("PATH"))]
+    package_dir: PathBuf,
+
+    /// Disable informational output to STDERR.
+    #[clap(long)]
+    quiet: bool,
+
+    /// Path to the template to be packaged.
+    #[clap(long)]
+    template_path: PathBuf,
+
+    #[clap(flatten)]
+    package: PackageArgs,
+}
+
+impl Args {
+    /// The path to the template, unless it's read from STDIN.
+    pub fn template_path(&self) -> Option<&Path> {
+        Some(self.template_path.as_path()).filter(|path| *path != Path::new("-"))
+    }
+}
+
+pub async fn main(region: Option<Region>, output: Output, args: Args) -> Result<(), Error> {
+    let region = get_region(region).await;
+    let mut template = Template::open(args.template_path.clone()).await?;
+    if args.package.inline_includes {
+        template.inline_includes().await?;
+    }
+    args.package
+        .process_local(
+            region.as_ref(),
+            args.quiet,
+            output.format,
+            &mut template,
+            &args.package_dir,
+        )
+        .await?;
+
+    println!("{template}");
+
+    Ok(())
+}
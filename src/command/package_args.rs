@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt,
+    io::{self, IsTerminal},
+    num::NonZeroUsize,
+    path::Path,
+    str::FromStr,
+};
+
+use aws_types::region::Region;
+use cloudformatious::Tag;
+use tokio::sync::Semaphore;
+
+use crate::{fmt::OutputFormat, package, s3, template, Error, Template};
+
+/// Options for packaging local paths referenced by a template.
+#[derive(Debug, clap::Args)]
+pub struct PackageArgs {
+    /// The ECR repository to push container images to.
+    ///
+    /// Required if any `AWS::Lambda::Function` has a local Dockerfile, or directory containing a
+    /// `Dockerfile`, as its `Code.ImageUri`. Images are built and pushed with `docker`, which must
+    /// already be authenticated with the repository.
+    #[clap(long, value_name("URI"))]
+    image_repository: Option<String>,
+
+    /// Inline `AWS::Include` transforms with local locations, rather than uploading them to S3.
+    ///
+    /// The content of each included snippet is merged directly into the template (and any nested
+    /// templates) before deployment. It's an error for a snippet to define a key that's already
+    /// present alongside the `Fn::Transform`.
+    #[clap(long)]
+    pub inline_includes: bool,
+
+    /// The S3 bucket to upload packages to.
+    ///
+    /// Not required unless there are references to local paths, other than container images, in
+    /// the template.
+    ///
+    /// If `auto`, a managed bucket named `cloudformatious-<account>-<region>` is used. If the
+    /// managed bucket doesn't exist, it's created (after confirmation, see `--yes`) with default
+    /// encryption and public access blocked. Packages in the managed bucket don't expire, since
+    /// nested stacks and rollbacks may still need them; use `gc-packages` to remove old packages.
+    #[clap(long)]
+    package_bucket: Option<String>,
+
+    /// The maximum number of packages to build, zip, and upload at once, including packages in
+    /// nested templates.
+    #[clap(long, value_name("N"), default_value("8"))]
+    package_concurrency: NonZeroUsize,
+
+    /// Vendor dependencies into zipped packages.
+    ///
+    /// When a zipped directory contains `requirements.txt` or `package.json`, dependencies are
+    /// installed with `pip install --target` or `npm ci --omit=dev` and added to the package.
+    /// Installed dependencies are cached under `$XDG_CACHE_HOME/cloudformatious`, keyed by the
+    /// lockfile contents. Individual resources can opt in or out with
+    /// `Metadata.Cloudformatious.Dependencies`.
+    #[clap(long)]
+    package_dependencies: bool,
+
+    /// Patterns for files to exclude from zipped packages.
+    ///
+    /// Patterns use `.gitignore` syntax and are matched relative to the root of each package. A
+    /// `.cfnignore` file in the root of a package is also honoured, and is itself excluded unless
+    /// it re-includes itself with `!/.cfnignore`. Individual resources can override these patterns
+    /// with a list of patterns in `Metadata.Cloudformatious.Exclude`.
+    #[clap(long, num_args(1..), value_name("GLOB"))]
+    package_exclude: Vec<String>,
+
+    /// The hash of package content used in keys.
+    ///
+    /// The MD5 hash is always used to verify the integrity of uploads, regardless of this setting.
+    #[clap(long, value_enum, default_value_t)]
+    package_key_hash: s3::KeyHash,
+
+    /// The template for the keys of uploaded packages.
+    ///
+    /// Available placeholders are `{prefix}` (from `--package-prefix`), `{resource}` (the logical
+    /// ID of the resource being packaged, or `AWS-Include` for `AWS::Include` snippets), `{hash}`
+    /// (the hash of the package content, which is required), and `{ext}` (the package's
+    /// extension, e.g. `.zip` or `.yaml`). For example, `{prefix}/{resource}/{hash}{ext}`. Empty
+    /// path segments are removed.
+    #[clap(long, value_name("TEMPLATE"), default_value_t)]
+    package_key_template: s3::KeyTemplate,
+
+    /// A prefix for any uploaded packages.
+    #[clap(long)]
+    package_prefix: Option<String>,
+
+    /// How to handle symbolic links when zipping packages.
+    ///
+    /// Regardless of this setting, file permissions (e.g. the executable bit) are preserved in
+    /// zipped packages.
+    #[clap(long, value_enum, default_value_t)]
+    package_symlinks: package::SymlinkPolicy,
+}
+
+/// Options for uploading packages to S3 while deploying.
+#[derive(Debug, clap::Args)]
+pub struct PackageUploadArgs {
+    /// Create the managed package bucket without asking for confirmation.
+    ///
+    /// Confirmation is read from STDIN, so this is required to create the bucket when STDIN isn't
+    /// a terminal, the template is read from STDIN, or `--no-input` is given.
+    #[clap(long)]
+    yes: bool,
+
+    #[clap(flatten)]
+    upload: UploadArgs,
+}
+
+/// Options for uploading packages to S3.
+#[derive(Debug, clap::Args)]
+pub struct UploadArgs {
+    /// A canned ACL to apply to uploaded packages.
+    #[clap(long, value_enum)]
+    package_acl: Option<s3::Acl>,
+
+    /// The KMS key to encrypt uploaded packages with.
+    ///
+    /// Implies `--package-sse aws:kms`, unless `--package-sse` is given.
+    #[clap(long, value_name("KEY_ID"))]
+    package_kms_key_id: Option<String>,
+
+    /// Packages larger than this many bytes are uploaded in parts.
+    ///
+    /// Parts are uploaded in parallel, and each part is retried if it fails. Packages larger than
+    /// 5 GiB must be uploaded in parts.
+    #[clap(long, value_name("BYTES"), default_value_t = s3::DEFAULT_MULTIPART_THRESHOLD)]
+    package_multipart_threshold: u64,
+
+    /// The size of each part, in bytes, when uploading packages in parts.
+    ///
+    /// The minimum part size is 5 MiB. Larger parts are used if needed to stay within the limit of
+    /// 10,000 parts.
+    #[clap(
+        long,
+        value_name("BYTES"),
+        default_value_t = s3::DEFAULT_MULTIPART_PART_SIZE,
+        value_parser(clap::value_parser!(u64).range(5 * 1024 * 1024..))
+    )]
+    package_multipart_part_size: u64,
+
+    /// Server-side encryption to request for uploaded packages.
+    ///
+    /// If not given, the bucket's default encryption applies.
+    #[clap(long, value_enum)]
+    package_sse: Option<s3::Encryption>,
+
+    /// Tags to apply to uploaded packages.
+    ///
+    /// Tags should be supplied either as `key=value` strings and/or as a JSON object (e.g.
+    /// `{"key1": "value1", "key2": "value2"}).
+    #[clap(long, num_args(1..), value_name("KEY=VALUE|JSON"))]
+    package_tags: Vec<TagArg>,
+}
+
+impl PackageArgs {
+    /// Package the local paths referenced by `template`, uploading them to S3.
+    pub async fn process(
+        &self,
+        upload: &PackageUploadArgs,
+        region: Option<&Region>,
+        no_input: bool,
+        quiet: bool,
+        output_format: OutputFormat,
+        template: &mut Template,
+    ) -> Result<(), Error> {
+        let kinds = package::target_kinds(template).await;
+        if !kinds.any() {
+            return Ok(());
+        }
+        let upload_config = self.upload_config(upload.upload.config());
+        if !kinds.s3 {
+            // Images are pushed to `--image-repository`, so no bucket or S3 client is needed
+            let destination = package::Destination::ImagesOnly;
+            let options = self.options(destination, "", &upload_config, quiet, output_format);
+            return package::process_template(&options, template).await;
+        }
+        let package_bucket = self.package_bucket(template)?;
+
+        let client = s3::Client::new(region.cloned(), no_input).await?;
+        let package_bucket = if package_bucket == s3::MANAGED_BUCKET {
+            client
+                .managed_bucket(upload.create_bucket(no_input, template))
+                .await?
+        } else {
+            package_bucket.to_string()
+        };
+
+        let destination = package::Destination::S3(&client);
+        let options = self.options(
+            destination,
+            &package_bucket,
+            &upload_config,
+            quiet,
+            output_format,
+        );
+        package::process_template(&options, template).await
+    }
+
+    /// Package the local paths referenced by `template` into `package_dir`.
+    ///
+    /// The template references packages as if they had been uploaded to S3, and a manifest is
+    /// written alongside the packages so they can be uploaded later. Container images can't be
+    /// packaged locally, since they're pushed to a registry.
+    pub async fn process_local(
+        &self,
+        region: Option<&Region>,
+        quiet: bool,
+        output_format: OutputFormat,
+        template: &mut Template,
+        package_dir: &Path,
+    ) -> Result<(), Error> {
+        let dir = package::LocalDir::new(package_dir, region);
+        if !package::target_kinds(template).await.any() {
+            return dir.write_manifest(self.package_bucket.as_deref()).await;
+        }
+
+        let package_bucket = self.package_bucket(template)?;
+        if package_bucket == s3::MANAGED_BUCKET {
+            return Err(Error::other(
+                "`--package-bucket auto` can't be used when packaging to a local directory",
+            ));
+        }
+
+        // Upload settings are given to `upload-packages`, only the key settings apply here
+        let upload = self.upload_config(s3::UploadConfig::default());
+        let destination = package::Destination::Dir(&dir);
+        let options = self.options(destination, package_bucket, &upload, quiet, output_format);
+        package::process_template(&options, template).await?;
+        dir.write_manifest(Some(package_bucket)).await
+    }
+
+    fn package_bucket(&self, template: &Template) -> Result<&str, Error> {
+        self.package_bucket.as_deref().ok_or_else(|| {
+            Error::other(format!(
+                concat!(
+                    "the `--package-bucket` option is required because template `{}` contains ",
+                    "references to local paths that will be packaged (use `--package-bucket auto` ",
+                    "for a managed bucket)"
+                ),
+                template.source()
+            ))
+        })
+    }
+
+    /// `upload` with the key hash and template from these options.
+    fn upload_config(&self, upload: s3::UploadConfig) -> s3::UploadConfig {
+        s3::UploadConfig {
+            key_hash: self.package_key_hash,
+            key_template: self.package_key_template.clone(),
+            ..upload
+        }
+    }
+
+    fn options<'a>(
+        &'a self,
+        destination: package::Destination<'a>,
+        package_bucket: &'a str,
+        upload: &'a s3::UploadConfig,
+        quiet: bool,
+        output_format: OutputFormat,
+    ) -> package::Options<'a> {
+        package::Options {
+            destination,
+            s3_bucket: package_bucket,
+            s3_prefix: self.package_prefix.as_deref(),
+            upload,
+            image_repository: self.image_repository.as_deref(),
+            inline_includes: self.inline_includes,
+            exclude: &self.package_exclude,
+            symlinks: self.package_symlinks,
+            dependencies: self.package_dependencies,
+            permits: Semaphore::new(self.package_concurrency.get()),
+            quiet,
+            output_format,
+        }
+    }
+}
+
+impl PackageUploadArgs {
+    /// Whether to create the managed bucket, and how to confirm it.
+    fn create_bucket(&self, no_input: bool, template: &Template) -> s3::CreateBucket {
+        if self.yes {
+            s3::CreateBucket::Yes
+        } else if no_input
+            || matches!(template.source(), template::Source::Stdin)
+            || !io::stdin().is_terminal()
+        {
+            s3::CreateBucket::NoPrompt
+        } else {
+            s3::CreateBucket::Prompt
+        }
+    }
+}
+
+impl UploadArgs {
+    /// Upload settings, with the default key hash and template.
+    pub fn config(&self) -> s3::UploadConfig {
+        s3::UploadConfig {
+            multipart_threshold: self.package_multipart_threshold,
+            multipart_part_size: self.package_multipart_part_size,
+            sse: self.package_sse,
+            kms_key_id: self.package_kms_key_id.clone(),
+            acl: self.package_acl,
+            tags: self
+                .package_tags
+                .iter()
+                .cloned()
+                .flatten()
+                .map(|tag| (tag.key, tag.value))
+                .collect(),
+            ..s3::UploadConfig::default()
+        }
+    }
+}
+
+/// Newtype for parsing tags.
+#[derive(Clone, Debug)]
+pub enum TagArg {
+    KeyValue(Tag),
+    Json(Vec<Tag>),
+}
+
+impl FromStr for TagArg {
+    type Err = InvalidTag;
+    fn from_str(tag: &str) -> Result<Self, Self::Err> {
+        // First try to parse as JSON
+        let tags: Result<HashMap<String, String>, _> = serde_json::from_str(tag);
+        if let Ok(tags) = tags {
+            return Ok(TagArg::Json(
+                tags.into_iter()
+                    .map(|(key, value)| Tag { key, value })
+                    .collect(),
+            ));
+        }
+
+        let kv: Vec<_> = tag.splitn(2, '=').collect();
+        let [key, value]: [_; 2] = kv.try_into().map_err(|_| InvalidTag(tag.to_string()))?;
+        Ok(Self::KeyValue(Tag {
+            key: key.to_string(),
+            value: value.to_string(),
+        }))
+    }
+}
+
+impl IntoIterator for TagArg {
+    type Item = Tag;
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+    fn into_iter(self) -> Self::IntoIter {
+        match self {
+            Self::KeyValue(tag) => vec![tag].into_iter(),
+            Self::Json(tags) => tags.into_iter(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidTag(String);
+
+impl fmt::Display for InvalidTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid tag `{}`, must be in the form `key=value` or a JSON object",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidTag {}
@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+
+use aws_types::region::Region;
+use tokio::fs::File;
+
+use super::package_args::UploadArgs;
+use crate::{
+    fmt::{print_package_message, Output},
+    package, s3, Error,
+};
+
+/// Upload packages written to a local directory by `package`.
+///
+/// Each package in the directory's `manifest.json` is uploaded to the bucket recorded in the
+/// manifest, with the same key, unless an object with that key is already present. The current
+/// region must match the region recorded in the manifest, since the packaged template references
+/// packages with that region's endpoint.
+///
+/// # Output
+///
+/// Progress is printed to STDERR, unless disabled with `--quiet`.
+#[derive(Debug, clap::Parser)]
+pub struct Args {
+    /// A flag to indicate that no input can be obtained.
+    ///
+    /// For example, this will cause the operation to fail if SSO authentication is configured and
+    /// not refereshed.
+    #[clap(long, default_value_t)]
+    no_input: bool,
+
+    /// The directory containing the packages and manifest.
+    #[clap(long, value_name("PATH"))]
+    package_dir: PathBuf,
+
+    /// Disable informational output to STDERR.
+    #[clap(long)]
+    quiet: bool,
+
+    #[clap(flatten)]
+    upload: UploadArgs,
+}
+
+pub async fn main(region: Option<Region>, output: Output, args: Args) -> Result<(), Error> {
+    let manifest = package::Manifest::read(&args.package_dir).await?;
+    if manifest.artifacts.is_empty() {
+        return Ok(());
+    }
+    let Some(bucket) = manifest.bucket.as_deref() else {
+        return Err(Error::other(format!(
+            "package manifest in `{}` has no bucket",
+            args.package_dir.display()
+        )));
+    };
+
+    let client = s3::Client::new(region, args.no_input).await?;
+    let current_region = client.region().map(Region::to_string);
+    if manifest.region.is_some() && manifest.region != current_region {
+        // The packaged template references packages with the manifest region's endpoint
+        return Err(Error::other(format!(
+            "packages in `{}` were packaged for region {}, but the current region is {}",
+            args.package_dir.display(),
+            manifest.region.as_deref().unwrap_or_default(),
+            current_region.as_deref().unwrap_or("not set"),
+        )));
+    }
+
+    let config = args.upload.config();
+    for artifact in &manifest.artifacts {
+        let key = &artifact.key;
+        let path = args.package_dir.join(key);
+        let file = File::open(&path).await.map_err(|error| {
+            Error::other(format!(
+                "couldn't open package `{}`: {error}",
+                path.display()
+            ))
+        })?;
+
+        let uri = format!("s3://{bucket}/{key}");
+        let progress = (!args.quiet).then(|| {
+            let resource_id = artifact.resource_id.clone();
+            let property = artifact.property.clone();
+            let print = move |message: &str| {
+                print_package_message(output.format, &resource_id, Some(&property), message);
+            };
+            Box::new(move |progress| match progress {
+                s3::UploadProgress::Skipped => print(&format!("{uri} skipped, already present")),
+                s3::UploadProgress::Uploading { uploaded, total } if uploaded == total => {
+                    print(&format!("uploaded {total} bytes to {uri}"));
+                }
+                s3::UploadProgress::Uploading { .. } => {}
+            }) as Box<dyn Fn(s3::UploadProgress) + Send + Sync>
+        });
+        client
+            .upload_to_key(bucket, key, file, &config, progress)
+            .await?;
+    }
+
+    Ok(())
+}
@@ -1,10 +1,25 @@
 use std::fmt;
 
-use cloudformatious::{status_reason::StatusReasonDetail, StackFailure, StackWarning};
+use cloudformatious::{
+    status_reason::StatusReasonDetail, ResourceStatus, StackEventDetails, StackFailure,
+    StackWarning,
+};
 use colored::Colorize;
+use serde_json::json;
 
 const NO_REASON: &str = "No reason";
 
+/// The format in which errors are reported.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// Human-readable, coloured text.
+    #[default]
+    Text,
+
+    /// A structured JSON document.
+    Json,
+}
+
 #[derive(Debug)]
 pub enum Error {
     Warning(StackWarning),
@@ -16,6 +31,37 @@ impl Error {
     pub fn other<E: Into<Box<dyn std::error::Error>>>(error: E) -> Self {
         Self::Other(error.into())
     }
+
+    /// Serialize the error to a structured JSON document.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::Warning(warning) => json!({
+                "kind": "warning",
+                "stackId": warning.stack_id,
+                "resourceEvents": resource_events_json(&warning.resource_events),
+            }),
+            Self::Failure(failure) => {
+                let reason = failure.stack_status_reason();
+                json!({
+                    "kind": "failure",
+                    "stackId": failure.stack_id,
+                    "stackStatus": failure.stack_status.to_string(),
+                    "stackStatusReason": failure.stack_status_reason,
+                    "stackStatusReasonDetail": reason.detail().as_ref().map(detail_json),
+                    "hint": reason.detail().and_then(|detail| get_hint(detail, str::to_string)),
+                    "resourceEvents": resource_events_json(&failure.resource_events),
+                })
+            }
+            Self::Other(error) => {
+                let chain = std::iter::successors(error.source(), |error| error.source());
+                json!({
+                    "kind": "other",
+                    "message": error.to_string(),
+                    "causes": chain.map(ToString::to_string).collect::<Vec<_>>(),
+                })
+            }
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -29,7 +75,11 @@ impl fmt::Display for Error {
                 writeln!(f, "   {} {}", "Status:".bold(), status.red())?;
                 writeln!(f, "   {} {}", "Reason:".bold(), failure.stack_status_reason)?;
 
-                if let Some(hint) = failure.stack_status_reason().detail().and_then(get_hint) {
+                if let Some(hint) = failure
+                    .stack_status_reason()
+                    .detail()
+                    .and_then(|detail| get_hint(detail, bold))
+                {
                     writeln!(f, "   {:<7} {}", "Hint:".bold(), hint)?;
                 }
 
@@ -53,7 +103,7 @@ impl fmt::Display for Error {
                         if let Some(hint) = event_details
                             .resource_status_reason()
                             .detail()
-                            .and_then(get_hint)
+                            .and_then(|detail| get_hint(detail, bold))
                         {
                             writeln!(f, "   {:<9} {}", "Hint:".bold(), hint)?;
                         }
@@ -83,20 +133,66 @@ impl std::error::Error for Error {
     }
 }
 
-fn get_hint(detail: StatusReasonDetail) -> Option<String> {
+fn resource_events_json(events: &[(ResourceStatus, StackEventDetails)]) -> serde_json::Value {
+    events
+        .iter()
+        .map(|(resource_status, event_details)| {
+            let reason = event_details.resource_status_reason();
+            json!({
+                "timestamp": event_details.timestamp().to_rfc3339(),
+                "logicalResourceId": event_details.logical_resource_id(),
+                "physicalResourceId": event_details.physical_resource_id(),
+                "resourceType": event_details.resource_type(),
+                "resourceStatus": resource_status.to_string(),
+                "resourceStatusReason": reason.inner(),
+                "resourceStatusReasonDetail": reason.detail().as_ref().map(detail_json),
+                "hint": reason.detail().and_then(|detail| get_hint(detail, str::to_string)),
+            })
+        })
+        .collect()
+}
+
+fn detail_json(detail: &StatusReasonDetail) -> serde_json::Value {
+    match detail {
+        StatusReasonDetail::CreationCancelled => json!({ "type": "CreationCancelled" }),
+        StatusReasonDetail::MissingPermission(detail) => json!({
+            "type": "MissingPermission",
+            "permission": detail.permission,
+            "principal": detail.principal,
+            "encodedAuthorizationMessage": detail
+                .encoded_authorization_message
+                .as_ref()
+                .map(|message| message.inner()),
+        }),
+        StatusReasonDetail::AuthorizationFailure(message) => json!({
+            "type": "AuthorizationFailure",
+            "encodedAuthorizationMessage": message.inner(),
+        }),
+        StatusReasonDetail::ResourceErrors(detail) => json!({
+            "type": "ResourceErrors",
+            "logicalResourceIds": detail.logical_resource_ids().collect::<Vec<_>>(),
+        }),
+        _ => serde_json::Value::Null,
+    }
+}
+
+fn bold(text: &str) -> String {
+    text.bold().to_string()
+}
+
+fn get_hint(detail: StatusReasonDetail, emphasize: fn(&str) -> String) -> Option<String> {
     match detail {
         StatusReasonDetail::CreationCancelled => Some("See preceding resource errors".to_string()),
         StatusReasonDetail::MissingPermission(detail) => Some(format!(
             "Give {} the {} permission",
             detail
                 .principal
-                .map(Colorize::bold)
-                .unwrap_or_else(|| "yourself".normal()),
-            detail.permission.bold()
+                .map_or_else(|| "yourself".to_string(), emphasize),
+            emphasize(detail.permission)
         )),
         StatusReasonDetail::ResourceErrors(detail) => Some(format!(
             "See resource error(s) for {}",
-            display_list(detail.logical_resource_ids().map(|id| id.bold()))
+            display_list(detail.logical_resource_ids().map(emphasize))
         )),
         _ => None,
     }
@@ -1,13 +1,78 @@
-use std::{borrow::Cow, iter};
+use std::{
+    borrow::Cow,
+    env,
+    io::{self, IsTerminal},
+    iter,
+};
 
 use cloudformatious::{change_set::ChangeSet, StackEvent, StackStatus, StatusSentiment};
 use colored::{ColoredString, Colorize};
 use futures_util::{Stream, StreamExt};
 
+use crate::ci::Ci;
+
 const AWS_CLOUDFORMATION_STACK: &str = "AWS::CloudFormation::Stack";
 const SHORT_UPDATE_COMPLETE_CLEANUP_IN_PROGRESS: &str = "UPDATE_CLEANUP_IN_PROGRESS";
 const SHORT_UPDATE_ROLLBACK_COMPLETE_CLEANUP_IN_PROGRESS: &str = "ROLLBACK_CLEANUP_IN_PROGRESS";
 
+/// The format in which stack events are printed.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned, coloured text, one event per line.
+    #[default]
+    Text,
+
+    /// Newline-delimited JSON, one event object per line.
+    Json,
+}
+
+/// When to use colour in output.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Use colour if STDERR is a terminal, unless overridden by `NO_COLOR` or `CLICOLOR_FORCE`.
+    #[default]
+    Auto,
+
+    /// Always use colour.
+    Always,
+
+    /// Never use colour.
+    Never,
+}
+
+impl ColorChoice {
+    /// Apply the choice globally, for all subsequent output.
+    pub fn apply(self) {
+        colored::control::set_override(self.should_colorize());
+    }
+
+    fn should_colorize(self) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => {
+                if env::var_os("CLICOLOR_FORCE").is_some_and(|value| value != "0") {
+                    true
+                } else if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+                    || env::var_os("CLICOLOR").is_some_and(|value| value == "0")
+                {
+                    false
+                } else {
+                    // All our coloured output goes to STDERR
+                    io::stderr().is_terminal()
+                }
+            }
+        }
+    }
+}
+
+/// Settings for how stack events are printed.
+#[derive(Clone, Copy, Debug)]
+pub struct Output {
+    pub format: OutputFormat,
+    pub ci: Option<Ci>,
+}
+
 pub struct Sizing {
     resource_status: usize,
     logical_resource_id: usize,
@@ -47,26 +112,94 @@ impl Default for Sizing {
     }
 }
 
-pub async fn print_events(sizing: &Sizing, mut events: impl Stream<Item = StackEvent> + Unpin) {
+pub async fn print_events(
+    output: Output,
+    title: &str,
+    sizing: &Sizing,
+    mut events: impl Stream<Item = StackEvent> + Unpin,
+) {
+    // Groups would break line-delimited JSON, so we only use them for text
+    let group = match (output.format, output.ci) {
+        (OutputFormat::Text, Some(ci)) => Some(ci.start_group(title)),
+        _ => None,
+    };
+
     while let Some(event) = events.next().await {
-        let logical_resource_id: Cow<'_, _> = if let Some(stack_alias) = event.stack_alias() {
-            [stack_alias, event.logical_resource_id()].join("/").into()
-        } else {
-            event.logical_resource_id().into()
-        };
-        eprintln!(
-            "{:?} {:resource_status_size$} {:logical_resource_id_size$} {:resource_type_size$} {}",
-            event.timestamp(),
-            colorize_status(&event),
-            logical_resource_id,
-            event.resource_type(),
-            event.resource_status_reason().unwrap_or("").bright_black(),
-            resource_status_size = sizing.resource_status,
-            logical_resource_id_size = sizing.logical_resource_id,
-            resource_type_size = sizing.resource_type,
-        );
+        match output.format {
+            OutputFormat::Text => print_event_text(sizing, &event),
+            OutputFormat::Json => print_event_json(&event),
+        }
+    }
+
+    if let Some(group) = group {
+        group.end();
+    }
+    if let OutputFormat::Text = output.format {
+        eprintln!();
+    }
+}
+
+/// Print a message about packaging `resource_id` to STDERR.
+///
+/// `property` is the packaged property, if the message is about it rather than, e.g., output from a
+/// build command. With `OutputFormat::Json` the message is printed as a JSON object, so STDERR
+/// remains newline-delimited JSON.
+pub fn print_package_message(
+    format: OutputFormat,
+    resource_id: &str,
+    property: Option<&str>,
+    message: &str,
+) {
+    match (format, property) {
+        (OutputFormat::Text, Some(property)) => eprintln!("{resource_id} | {property}: {message}"),
+        (OutputFormat::Text, None) => eprintln!("{resource_id} | {message}"),
+        (OutputFormat::Json, _) => {
+            let message_json = serde_json::json!({
+                "logicalResourceId": resource_id,
+                "property": property,
+                "message": message,
+            });
+            eprintln!("{message_json}");
+        }
     }
-    eprintln!();
+}
+
+fn print_event_text(sizing: &Sizing, event: &StackEvent) {
+    let logical_resource_id: Cow<'_, _> = if let Some(stack_alias) = event.stack_alias() {
+        [stack_alias, event.logical_resource_id()].join("/").into()
+    } else {
+        event.logical_resource_id().into()
+    };
+    eprintln!(
+        "{:?} {:resource_status_size$} {:logical_resource_id_size$} {:resource_type_size$} {}",
+        event.timestamp(),
+        colorize_status(event),
+        logical_resource_id,
+        event.resource_type(),
+        event.resource_status_reason().unwrap_or("").bright_black(),
+        resource_status_size = sizing.resource_status,
+        logical_resource_id_size = sizing.logical_resource_id,
+        resource_type_size = sizing.resource_type,
+    );
+}
+
+fn print_event_json(event: &StackEvent) {
+    let sentiment = match event.resource_status().sentiment() {
+        StatusSentiment::Positive => "positive",
+        StatusSentiment::Neutral => "neutral",
+        StatusSentiment::Negative => "negative",
+    };
+    let event_json = serde_json::json!({
+        "timestamp": event.timestamp().to_rfc3339(),
+        "status": event.resource_status().to_string(),
+        "sentiment": sentiment,
+        "logicalResourceId": event.logical_resource_id(),
+        "physicalResourceId": event.physical_resource_id(),
+        "resourceType": event.resource_type(),
+        "resourceStatusReason": event.resource_status_reason(),
+        "stackAlias": event.stack_alias(),
+    });
+    eprintln!("{event_json}");
 }
 
 fn colorize_status(event: &StackEvent) -> ColoredString {
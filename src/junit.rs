@@ -0,0 +1,239 @@
+use std::{fmt, path::Path};
+
+use cloudformatious::{change_set::ChangeSet, StackEvent};
+use tokio::fs;
+
+use crate::Error;
+
+/// A JUnit XML report with a test case per resource affected by a stack operation.
+///
+/// A test case fails if its resource entered a `*_FAILED` state at any point during the operation,
+/// even if it subsequently recovered (e.g. it was deleted during rollback). Test cases for
+/// resources that never received an event are marked as skipped.
+#[derive(Debug)]
+pub struct Report {
+    stack_name: String,
+    cases: Vec<TestCase>,
+}
+
+#[derive(Debug)]
+struct TestCase {
+    name: String,
+    resource_type: String,
+    last_status: Option<String>,
+    failure: Option<Failure>,
+}
+
+#[derive(Debug)]
+struct Failure {
+    status: String,
+    reason: String,
+}
+
+impl Report {
+    pub fn new(stack_name: impl Into<String>) -> Self {
+        Self {
+            stack_name: stack_name.into(),
+            cases: Vec::new(),
+        }
+    }
+
+    /// Create a report with a test case for each resource in `change_set`.
+    pub fn for_change_set(change_set: &ChangeSet) -> Self {
+        let mut report = Self::new(&change_set.stack_name);
+        for change in &change_set.changes {
+            report.case_mut(&change.logical_resource_id, &change.resource_type);
+        }
+        report
+    }
+
+    /// Record a stack event against the relevant test case.
+    pub fn record(&mut self, event: &StackEvent) {
+        let StackEvent::Resource {
+            resource_status, ..
+        } = event
+        else {
+            return;
+        };
+
+        let name = match event.stack_alias() {
+            Some(stack_alias) => format!("{stack_alias}/{}", event.logical_resource_id()),
+            None => event.logical_resource_id().to_string(),
+        };
+        let status = resource_status.to_string();
+        let case = self.case_mut(&name, event.resource_type());
+
+        if case.failure.is_none() && status.ends_with("_FAILED") {
+            case.failure = Some(Failure {
+                status: status.clone(),
+                reason: event
+                    .resource_status_reason()
+                    .unwrap_or("No reason")
+                    .to_string(),
+            });
+        }
+        case.last_status = Some(status);
+    }
+
+    /// Record an error that prevented the operation from completing as a failed test case.
+    pub fn record_error(&mut self, error: &impl fmt::Display) {
+        let name = self.stack_name.clone();
+        let case = self.case_mut(&name, "AWS::CloudFormation::Stack");
+        case.failure = Some(Failure {
+            status: "ERROR".to_string(),
+            reason: error.to_string(),
+        });
+        case.last_status = Some("ERROR".to_string());
+    }
+
+    pub async fn write(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, self.to_string()).await.map_err(|error| {
+            Error::other(format!(
+                "couldn't write JUnit report `{}`: {error}",
+                path.display()
+            ))
+        })
+    }
+
+    fn case_mut(&mut self, name: &str, resource_type: &str) -> &mut TestCase {
+        let index = match self.cases.iter().position(|case| case.name == name) {
+            Some(index) => index,
+            None => {
+                self.cases.push(TestCase {
+                    name: name.to_string(),
+                    resource_type: resource_type.to_string(),
+                    last_status: None,
+                    failure: None,
+                });
+                self.cases.len() - 1
+            }
+        };
+        &mut self.cases[index]
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tests = self.cases.len();
+        let failures = self
+            .cases
+            .iter()
+            .filter(|case| case.failure.is_some())
+            .count();
+        let skipped = self
+            .cases
+            .iter()
+            .filter(|case| case.last_status.is_none())
+            .count();
+        let counts = format!(r#"tests="{tests}" failures="{failures}" skipped="{skipped}""#);
+
+        writeln!(f, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(f, r#"<testsuites name="cloudformatious" {counts}>"#)?;
+        writeln!(
+            f,
+            r#"  <testsuite name="{}" {counts}>"#,
+            escape(&self.stack_name)
+        )?;
+        for case in &self.cases {
+            write!(
+                f,
+                r#"    <testcase name="{}" classname="{}""#,
+                escape(&case.name),
+                escape(&case.resource_type)
+            )?;
+            match (&case.failure, &case.last_status) {
+                (Some(failure), _) => {
+                    writeln!(f, ">")?;
+                    writeln!(
+                        f,
+                        r#"      <failure type="{}" message="{}">{}</failure>"#,
+                        escape(&failure.status),
+                        escape(&failure.reason),
+                        escape(&failure.reason)
+                    )?;
+                    writeln!(f, "    </testcase>")?;
+                }
+                (None, None) => {
+                    writeln!(f, ">")?;
+                    writeln!(f, "      <skipped/>")?;
+                    writeln!(f, "    </testcase>")?;
+                }
+                (None, Some(_)) => writeln!(f, "/>")?,
+            }
+        }
+        writeln!(f, "  </testsuite>")?;
+        writeln!(f, "</testsuites>")
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[test]
+fn test_report() {
+    use cloudformatious::{ResourceStatus, StackEventDetails};
+
+    let event =
+        |logical_resource_id: &str, resource_status, reason: Option<&str>| StackEvent::Resource {
+            resource_status,
+            details: StackEventDetails {
+                client_request_token: None,
+                event_id: "event".to_string(),
+                logical_resource_id: logical_resource_id.to_string(),
+                physical_resource_id: None,
+                resource_status_reason: reason.map(str::to_string),
+                resource_type: "AWS::SQS::Queue".to_string(),
+                stack_id: "stack-id".to_string(),
+                stack_name: "stack".to_string(),
+                stack_alias: None,
+                timestamp: chrono::Utc::now(),
+            },
+        };
+
+    let mut report = Report::new("stack");
+    report.case_mut("Skipped", "AWS::SNS::Topic");
+    report.record(&event("Queue", ResourceStatus::CreateComplete, None));
+    report.record(&event(
+        "Failed<1>",
+        ResourceStatus::CreateFailed,
+        Some(r#"Invalid "name" & more"#),
+    ));
+    // Later events don't replace the first failure
+    report.record(&event(
+        "Failed<1>",
+        ResourceStatus::DeleteComplete,
+        Some("Deleted"),
+    ));
+
+    assert_eq!(
+        report.to_string(),
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="cloudformatious" tests="3" failures="1" skipped="1">
+  <testsuite name="stack" tests="3" failures="1" skipped="1">
+    <testcase name="Skipped" classname="AWS::SNS::Topic">
+      <skipped/>
+    </testcase>
+    <testcase name="Queue" classname="AWS::SQS::Queue"/>
+    <testcase name="Failed&lt;1&gt;" classname="AWS::SQS::Queue">
+      <failure type="CREATE_FAILED" message="Invalid &quot;name&quot; &amp; more">Invalid &quot;name&quot; &amp; more</failure>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+    );
+}
+
+#[test]
+fn test_report_record_error() {
+    let mut report = Report::new("stack");
+    report.record_error(&"template is invalid");
+    assert!(report.to_string().contains(
+        r#"<testcase name="stack" classname="AWS::CloudFormation::Stack">
+      <failure type="ERROR" message="template is invalid">"#
+    ));
+}
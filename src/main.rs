@@ -1,17 +1,27 @@
+mod ci;
 mod client;
 mod command;
 mod error;
 mod fmt;
+mod junit;
 mod package;
 mod s3;
 mod template;
 
-use std::{convert::Infallible, process};
+use std::{
+    convert::Infallible,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
 
 use aws_types::region::Region;
 use clap::Parser;
 
-use self::{error::Error, template::Template};
+use self::{
+    error::{Error, ErrorFormat},
+    template::Template,
+};
 
 /// A CloudFormation CLI that won't make you cry.
 ///
@@ -26,6 +36,40 @@ struct Args {
     #[clap(long, env = "AWS_REGION", value_parser = parse_region)]
     region: Option<Region>,
 
+    /// When to use colour in output.
+    ///
+    /// `auto` honours the `NO_COLOR` and `CLICOLOR_FORCE` environment variables, and otherwise
+    /// uses colour only if STDERR is a terminal.
+    #[clap(long, value_enum, default_value_t)]
+    color: fmt::ColorChoice,
+
+    /// The format in which stack events and packaging progress are printed to STDERR.
+    ///
+    /// `json` prints each event or message as a single-line JSON object, which is easier to
+    /// consume from log aggregators and other tooling.
+    #[clap(long, value_enum, default_value_t)]
+    output_format: fmt::OutputFormat,
+
+    /// The format in which errors are printed to STDERR.
+    ///
+    /// `json` prints a structured document including the stack status, each failed resource, and
+    /// any hints.
+    #[clap(long, value_enum, default_value_t)]
+    error_format: ErrorFormat,
+
+    /// Write a JSON error report to the given path if the command fails.
+    ///
+    /// This is written in addition to the error printed to STDERR.
+    #[clap(long, value_name = "PATH")]
+    error_report: Option<PathBuf>,
+
+    /// Emit CI-specific output, such as annotations for failed resources and collapsible groups
+    /// of stack events.
+    ///
+    /// By default, GitHub Actions and GitLab CI are detected from the environment.
+    #[clap(long, value_enum, default_value_t)]
+    ci: ci::CiArg,
+
     #[clap(subcommand)]
     command: command::Command,
 }
@@ -33,9 +77,34 @@ struct Args {
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    args.color.apply();
+
+    let ci = ci::Ci::detect(args.ci);
+    let output = fmt::Output {
+        format: args.output_format,
+        ci,
+    };
+    let template_path = args.command.template_path().map(Path::to_path_buf);
+
+    if let Err(error) = command::main(args.region, output, args.command).await {
+        if let (Some(ci), Error::Failure(failure)) = (ci, &error) {
+            ci.annotate_failure(failure, template_path.as_deref());
+        }
+
+        if let Some(path) = &args.error_report {
+            let report = serde_json::to_string_pretty(&error.to_json()).expect("oh no");
+            if let Err(report_error) = fs::write(path, report) {
+                eprintln!(
+                    "couldn't write error report `{}`: {report_error}",
+                    path.display()
+                );
+            }
+        }
 
-    if let Err(error) = command::main(args.region, args.command).await {
-        eprintln!("{}", error);
+        match args.error_format {
+            ErrorFormat::Text => eprintln!("{}", error),
+            ErrorFormat::Json => eprintln!("{}", error.to_json()),
+        }
         process::exit(match error {
             Error::Warning(_) => 3,
             Error::Failure(_) => 4,
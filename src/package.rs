@@ -1,149 +1,616 @@
+mod build;
+mod cache;
+mod dependencies;
+mod image;
+mod local;
+mod native;
+
 use std::{
     collections::HashMap,
     fmt,
     iter::FromIterator,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
 };
 
-use async_zip::{write::ZipFileWriter, Compression, ZipEntryBuilder};
-use chrono::{DateTime, Utc};
+use async_zip::{write::ZipFileWriter, Compression, ZipEntryBuilder, ZipEntryBuilderExt};
+use chrono::{DateTime, TimeZone, Utc};
 use futures_util::{stream, TryStreamExt};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use serde_yaml::Value as YamlValue;
 use tokio::{
     fs::{self, File},
     io::{self, AsyncSeekExt, AsyncWriteExt, BufWriter},
+    sync::{Semaphore, SemaphorePermit},
+};
+
+use crate::{
+    fmt::{print_package_message, OutputFormat},
+    s3, template, Error, Template,
 };
 
-use crate::{s3, template, Error, Template};
+pub use self::local::{LocalDir, Manifest};
 
 #[derive(Debug)]
 pub struct PackageableProperty {
     resource_type: &'static str,
     path: &'static [&'static str],
-    strategy: PackageStrategy,
-    s3_ref: fn(String, s3::UploadOutput) -> serde_yaml::Value,
+    packaging: Packaging,
+}
+
+/// How a packageable property is packaged, and how the result is referenced.
+#[derive(Debug)]
+enum Packaging {
+    /// Package the path with `strategy`, upload it to S3, and reference it with `s3_ref`.
+    S3 {
+        strategy: PackageStrategy,
+        s3_ref: fn(String, s3::UploadOutput) -> serde_yaml::Value,
+    },
+
+    /// Build a container image and push it to `--image-repository`.
+    Image,
 }
 
 #[derive(Clone, Copy, Debug)]
 enum PackageStrategy {
+    /// Upload a single file as-is.
+    File,
     Template,
     Zip,
+
+    /// Compile a function binary and zip it as `bootstrap`.
+    ///
+    /// This is never used in `PACKAGEABLE_PROPERTIES`, but is selected instead of `Zip` when a
+    /// resource has `Metadata.Cloudformatious.Builder`.
+    Native(native::Builder),
 }
 
 const PACKAGEABLE_PROPERTIES: &[PackageableProperty] = &[
     PackageableProperty {
         resource_type: "AWS::CloudFormation::Stack",
         path: &["TemplateURL"],
-        strategy: PackageStrategy::Template,
-        s3_ref: |_bucket, upload| upload.uri.into(),
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::Template,
+            s3_ref: |_bucket, upload| upload.uri.into(),
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::CloudFormation::StackSet",
+        path: &["TemplateURL"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::Template,
+            s3_ref: |_bucket, upload| upload.uri.into(),
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::ApiGateway::RestApi",
+        path: &["BodyS3Location"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::File,
+            s3_ref: bucket_key_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::AppSync::GraphQLSchema",
+        path: &["DefinitionS3Location"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::File,
+            s3_ref: s3_uri_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::AppSync::Resolver",
+        path: &["RequestMappingTemplateS3Location"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::File,
+            s3_ref: s3_uri_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::AppSync::Resolver",
+        path: &["ResponseMappingTemplateS3Location"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::File,
+            s3_ref: s3_uri_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::Glue::Job",
+        path: &["Command", "ScriptLocation"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::File,
+            s3_ref: s3_uri_ref,
+        },
+    },
+    // More specific paths must come before their prefixes, since the first match is used
+    PackageableProperty {
+        resource_type: "AWS::Lambda::Function",
+        path: &["Code", "ImageUri"],
+        packaging: Packaging::Image,
     },
     PackageableProperty {
         resource_type: "AWS::Lambda::Function",
         path: &["Code"],
-        strategy: PackageStrategy::Zip,
-        s3_ref: |bucket, upload| {
-            serde_yaml::Mapping::from_iter([
-                (
-                    serde_yaml::Value::String("S3Bucket".to_string()),
-                    serde_yaml::Value::String(bucket),
-                ),
-                (
-                    serde_yaml::Value::String("S3Key".to_string()),
-                    serde_yaml::Value::String(upload.key),
-                ),
-            ])
-            .into()
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::Zip,
+            s3_ref: s3_bucket_s3_key_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::Lambda::LayerVersion",
+        path: &["Content"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::Zip,
+            s3_ref: s3_bucket_s3_key_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::Serverless::Api",
+        path: &["DefinitionUri"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::File,
+            s3_ref: bucket_key_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::Serverless::Application",
+        path: &["Location"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::Template,
+            s3_ref: |_bucket, upload| upload.uri.into(),
         },
     },
     PackageableProperty {
         resource_type: "AWS::Serverless::Function",
         path: &["CodeUri"],
-        strategy: PackageStrategy::Zip,
-        s3_ref: |bucket, upload| {
-            serde_yaml::Mapping::from_iter([
-                (
-                    serde_yaml::Value::String("Bucket".to_string()),
-                    serde_yaml::Value::String(bucket),
-                ),
-                (
-                    serde_yaml::Value::String("Key".to_string()),
-                    serde_yaml::Value::String(upload.key),
-                ),
-            ])
-            .into()
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::Zip,
+            s3_ref: bucket_key_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::Serverless::LayerVersion",
+        path: &["ContentUri"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::Zip,
+            s3_ref: bucket_key_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::Serverless::StateMachine",
+        path: &["DefinitionUri"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::File,
+            s3_ref: bucket_key_ref,
+        },
+    },
+    PackageableProperty {
+        resource_type: "AWS::StepFunctions::StateMachine",
+        path: &["DefinitionS3Location"],
+        packaging: Packaging::S3 {
+            strategy: PackageStrategy::File,
+            s3_ref: bucket_key_ref,
         },
     },
 ];
 
+/// The `Location` parameter of an `AWS::Include` transform.
+const INCLUDE_LOCATION: PackageableProperty = PackageableProperty {
+    resource_type: "AWS::Include",
+    path: &["Location"],
+    packaging: Packaging::S3 {
+        strategy: PackageStrategy::File,
+        s3_ref: s3_uri_ref,
+    },
+};
+
+/// The key in resource `Metadata` under which per-resource packaging settings are read.
+const METADATA_KEY: &str = "Cloudformatious";
+
+/// The name of the file, in the root of a zipped package, that lists paths to exclude.
+const IGNORE_FILE: &str = ".cfnignore";
+
+/// The earliest date that can be stored in a zip entry, used for all entries in zipped packages.
+///
+/// Zip entries store dates in DOS format, with years relative to 1980, so earlier dates wrap.
+fn dos_epoch() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(1980, 1, 1, 0, 0, 0).unwrap()
+}
+
+/// The Unix mode recorded for symbolic links in zipped packages.
+const SYMLINK_MODE: u16 = 0o120777;
+
+/// URI schemes that indicate a property value is not a local path.
+const REMOTE_SCHEMES: &[&str] = &["s3://", "http://", "https://"];
+
+/// An S3 reference in the form `{ S3Bucket: ..., S3Key: ... }`.
+fn s3_bucket_s3_key_ref(bucket: String, upload: s3::UploadOutput) -> YamlValue {
+    serde_yaml::Mapping::from_iter([
+        (
+            YamlValue::String("S3Bucket".to_string()),
+            YamlValue::String(bucket),
+        ),
+        (
+            YamlValue::String("S3Key".to_string()),
+            YamlValue::String(upload.key),
+        ),
+    ])
+    .into()
+}
+
+/// An S3 reference in the form `s3://bucket/key`.
+fn s3_uri_ref(bucket: String, upload: s3::UploadOutput) -> YamlValue {
+    YamlValue::String(format!("s3://{bucket}/{}", upload.key))
+}
+
+/// An S3 reference in the form `{ Bucket: ..., Key: ... }`.
+fn bucket_key_ref(bucket: String, upload: s3::UploadOutput) -> YamlValue {
+    serde_yaml::Mapping::from_iter([
+        (
+            YamlValue::String("Bucket".to_string()),
+            YamlValue::String(bucket),
+        ),
+        (
+            YamlValue::String("Key".to_string()),
+            YamlValue::String(upload.key),
+        ),
+    ])
+    .into()
+}
+
 pub struct Target<'y> {
     resource_id: &'y str,
     property: &'static PackageableProperty,
     target: &'y mut YamlValue,
     src: Src,
+
+    /// The resource's `Metadata.Cloudformatious` settings, if any.
+    metadata: YamlValue,
+
+    /// The first of the resource's `Architectures`, if any.
+    architecture: Option<String>,
+}
+
+impl Target<'_> {
+    /// The strategy to package this target with, taking resource metadata into account.
+    fn strategy(&self, strategy: PackageStrategy) -> Result<PackageStrategy, Error> {
+        match strategy {
+            PackageStrategy::Zip => Ok(native::Builder::from_metadata(self)?
+                .map_or(PackageStrategy::Zip, PackageStrategy::Native)),
+            strategy => Ok(strategy),
+        }
+    }
+
+    /// Whether the target refers to something that should be packaged.
+    ///
+    /// Image URIs are only local if they exist, since registry URIs have no scheme.
+    async fn is_local(&self) -> bool {
+        let Src::Local(path) = &self.src;
+        match self.property.packaging {
+            Packaging::S3 { .. } => true,
+            Packaging::Image => fs::try_exists(path).await.unwrap_or(false),
+        }
+    }
 }
 
 enum Src {
     Local(PathBuf),
 }
 
-pub fn targets(template: &mut Template) -> impl Iterator<Item = Target<'_>> + '_ {
-    // Build a map of packageable property for easy lookup
-    let packageable_properties: HashMap<_, _> = PACKAGEABLE_PROPERTIES
-        .iter()
-        .map(|prop| (prop.resource_type, prop))
-        .collect();
+/// Settings for packaging a template.
+pub struct Options<'a> {
+    pub destination: Destination<'a>,
+    pub s3_bucket: &'a str,
+    pub s3_prefix: Option<&'a str>,
+    pub upload: &'a s3::UploadConfig,
 
-    let package_dir = match template.source() {
-        template::Source::Path(path) => path
-            .parent()
-            .expect("file path must have a parent")
-            .to_path_buf(),
-        template::Source::Stdin => PathBuf::from(""),
+    /// The ECR repository URI to push container images to.
+    pub image_repository: Option<&'a str>,
+
+    /// Inline local `AWS::Include` snippets in nested templates, rather than uploading them.
+    pub inline_includes: bool,
+
+    /// Patterns, in `.gitignore` syntax, for files to exclude from zipped packages.
+    ///
+    /// Resources can override these with `Metadata.Cloudformatious.Exclude`.
+    pub exclude: &'a [String],
+
+    /// How to handle symbolic links in zipped packages.
+    pub symlinks: SymlinkPolicy,
+
+    /// Vendor dependencies from `requirements.txt` or `package.json` into zipped packages.
+    ///
+    /// Resources can override this with `Metadata.Cloudformatious.Dependencies`.
+    pub dependencies: bool,
+
+    /// Limits the number of packages being processed (from build to upload) at once.
+    ///
+    /// This is shared by nested templates, so the limit applies across the whole template tree.
+    pub permits: Semaphore,
+
+    /// Disable build output and progress messages on STDERR.
+    pub quiet: bool,
+
+    /// The format of build output and progress messages.
+    pub output_format: OutputFormat,
+}
+
+/// Where to put packages.
+#[derive(Clone, Copy)]
+pub enum Destination<'a> {
+    /// Upload packages to S3.
+    S3(&'a s3::Client),
+
+    /// Write packages to a local directory, to be uploaded later.
+    Dir(&'a LocalDir),
+
+    /// Nowhere, since the template only references container images (see `TargetKinds`).
+    ImagesOnly,
+}
+
+/// How to handle symbolic links when zipping a directory.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Package the file or directory the link points to.
+    #[default]
+    Follow,
+
+    /// Store the link itself in the zip.
+    Preserve,
+
+    /// Fail if a symbolic link is found.
+    Error,
+}
+
+/// The kinds of local paths referenced by a template.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TargetKinds {
+    /// Whether there are any paths to package and upload to S3.
+    pub s3: bool,
+
+    /// Whether there are any container images to build and push.
+    pub images: bool,
+}
+
+impl TargetKinds {
+    pub fn any(self) -> bool {
+        self.s3 || self.images
+    }
+}
+
+/// Find the kinds of local paths in `template` that should be packaged.
+pub async fn target_kinds(template: &mut Template) -> TargetKinds {
+    let mut kinds = TargetKinds {
+        s3: include_targets(template).next().is_some(),
+        images: false,
     };
+    for target in targets(template).await {
+        match target.property.packaging {
+            Packaging::S3 { .. } => kinds.s3 = true,
+            Packaging::Image => kinds.images = true,
+        }
+    }
+    kinds
+}
 
-    template.resources_mut().filter_map(move |resource| {
-        let property = packageable_properties.get(resource.resource_type())?;
-        let (resource_id, _, properties) = resource.into_parts_mut();
-        let target = property
-            .path
-            .iter()
-            .try_fold(properties, |props, key| props.get_mut(key))?;
-        let path = package_dir.join(target.as_str()?);
+/// Package all the local paths referenced by `template`, replacing them with S3 references.
+pub async fn process_template(options: &Options<'_>, template: &mut Template) -> Result<(), Error> {
+    process(options, include_targets(template)).await?;
+    process(options, targets(template).await).await
+}
 
+fn include_targets(template: &mut Template) -> impl Iterator<Item = Target<'_>> + '_ {
+    let package_dir = package_dir(template);
+
+    template.include_locations_mut().filter_map(move |target| {
+        let path = local_path(&package_dir, target.as_str()?)?;
         Some(Target {
-            resource_id,
-            property,
+            resource_id: INCLUDE_LOCATION.resource_type,
+            property: &INCLUDE_LOCATION,
             target,
             src: Src::Local(path),
+            metadata: YamlValue::Null,
+            architecture: None,
         })
     })
 }
 
-pub async fn process(
-    client: &s3::Client,
-    s3_bucket: &str,
-    s3_prefix: Option<&str>,
+async fn targets(template: &mut Template) -> Vec<Target<'_>> {
+    let mut targets = Vec::new();
+    for target in candidate_targets(template) {
+        if target.is_local().await {
+            targets.push(target);
+        }
+    }
+    targets
+}
+
+/// Targets for all the packageable properties in `template` with local paths.
+///
+/// Not all of these are necessarily local, see `Target::is_local`.
+fn candidate_targets(template: &mut Template) -> impl Iterator<Item = Target<'_>> + '_ {
+    // Build a map of packageable properties for easy lookup
+    let mut packageable_properties: HashMap<_, Vec<_>> = HashMap::new();
+    for prop in PACKAGEABLE_PROPERTIES {
+        packageable_properties
+            .entry(prop.resource_type)
+            .or_default()
+            .push(prop);
+    }
+
+    let package_dir = package_dir(template);
+
+    template.resources_mut().flat_map(move |resource| {
+        let resource_properties = packageable_properties
+            .get(resource.resource_type())
+            .cloned()
+            .unwrap_or_default();
+        let package_dir = package_dir.clone();
+        let metadata = resource
+            .metadata()
+            .and_then(|metadata| metadata.get(METADATA_KEY))
+            .cloned()
+            .unwrap_or(YamlValue::Null);
+        let (resource_id, _, properties) = resource.into_parts_mut();
+        let architecture = properties
+            .get("Architectures")
+            .and_then(|architectures| architectures.get(0))
+            .and_then(YamlValue::as_str)
+            .map(str::to_string);
+
+        // Iterating the top-level properties lets us borrow each packageable property mutably
+        properties
+            .as_mapping_mut()
+            .into_iter()
+            .flat_map(serde_yaml::Mapping::iter_mut)
+            .filter_map(move |(key, value)| {
+                let (property, target) = find_property(&resource_properties, key, value)?;
+                let path = local_path(&package_dir, target.as_str()?)?;
+
+                Some(Target {
+                    resource_id,
+                    property,
+                    target,
+                    src: Src::Local(path),
+                    metadata: metadata.clone(),
+                    architecture: architecture.clone(),
+                })
+            })
+    })
+}
+
+/// Find the first of `properties` under the top-level property `key` with a string value.
+///
+/// Several properties can share a top-level property (e.g. `Code` and `Code.ImageUri`), so each is
+/// tried in turn.
+fn find_property<'y>(
+    properties: &[&'static PackageableProperty],
+    key: &YamlValue,
+    value: &'y mut YamlValue,
+) -> Option<(&'static PackageableProperty, &'y mut YamlValue)> {
+    let property = properties
+        .iter()
+        .filter(|prop| key.as_str() == Some(prop.path[0]))
+        .find(|prop| {
+            prop.path[1..]
+                .iter()
+                .try_fold(&*value, |props, key| props.get(key))
+                .is_some_and(YamlValue::is_string)
+        })?;
+    let target = property.path[1..]
+        .iter()
+        .try_fold(value, |props, key| props.get_mut(key))?;
+    Some((property, target))
+}
+
+/// The directory relative to which local paths in `template` are resolved.
+fn package_dir(template: &Template) -> PathBuf {
+    match template.source() {
+        template::Source::Path(path) => path
+            .parent()
+            .expect("file path must have a parent")
+            .to_path_buf(),
+        template::Source::Stdin => PathBuf::from(""),
+    }
+}
+
+/// Resolve `path` relative to `package_dir`, unless it's a remote URI.
+pub(crate) fn local_path(package_dir: &Path, path: &str) -> Option<PathBuf> {
+    if REMOTE_SCHEMES.iter().any(|scheme| path.starts_with(scheme)) {
+        return None;
+    }
+    Some(package_dir.join(path))
+}
+
+async fn process(
+    options: &Options<'_>,
     targets: impl IntoIterator<Item = Target<'_>>,
 ) -> Result<(), Error> {
     stream::iter(targets.into_iter().map(Ok::<_, Error>))
-        .try_for_each_concurrent(None, |target| async move {
-            let file = match target.property.strategy {
+        .try_for_each_concurrent(None, |mut target| async move {
+            if matches!(target.property.packaging, Packaging::Image)
+                && matches!(options.destination, Destination::Dir(_))
+            {
+                // Images are pushed to a registry, so there's nothing to write to the directory
+                return upload_err(
+                    &target,
+                    "container images can't be packaged to a local directory, use `apply-stack` \
+                     to build and push them",
+                );
+            }
+
+            // The permit is held for the whole pipeline, so at most `permits` packages are open
+            let permit = permit(options).await;
+            build::run(options, &mut target).await?;
+
+            let (strategy, s3_ref) = match target.property.packaging {
+                Packaging::S3 { strategy, s3_ref } => (target.strategy(strategy)?, s3_ref),
+                Packaging::Image => {
+                    let image = image::package_image(options, &target).await?;
+                    *target.target = YamlValue::String(image);
+                    return Ok(());
+                }
+            };
+
+            let cache = cache::Entry::for_target(options, &target, strategy).await;
+            if let Some(upload) = match &cache {
+                Some(cache) => cache.get(options).await,
+                None => None,
+            } {
+                report(
+                    options,
+                    &target,
+                    format_args!(
+                        "skipped, unchanged since s3://{}/{}",
+                        options.s3_bucket, upload.key
+                    ),
+                );
+                *target.target = s3_ref(options.s3_bucket.to_string(), upload);
+                return Ok(());
+            }
+
+            let (file, _permit) = match strategy {
+                PackageStrategy::File => (package_file(&target).await?, permit),
                 PackageStrategy::Template => {
-                    package_template(client, s3_bucket, s3_prefix, &target).await?
+                    // The nested template's packages need permits of their own, so holding one
+                    // while waiting for them could deadlock
+                    drop(permit);
+                    let file = package_template(options, &target).await?;
+                    (file, self::permit(options).await)
                 }
-                PackageStrategy::Zip => package_zip(&target).await?,
+                PackageStrategy::Zip => (package_zip(options, &target).await?, permit),
+                PackageStrategy::Native(builder) => (
+                    native::package_native(options, &mut target, builder).await?,
+                    permit,
+                ),
             };
 
-            let upload = client
-                .upload(s3::UploadRequest {
-                    bucket: s3_bucket,
-                    prefix: s3_prefix,
-                    file,
-                })
-                .await
-                .or_else(|error| upload_err(&target, error))?;
+            let extension = extension(&target, strategy);
+            let upload = match options.destination {
+                Destination::S3(client) => client
+                    .upload(s3::UploadRequest {
+                        bucket: options.s3_bucket,
+                        prefix: options.s3_prefix,
+                        file,
+                        config: options.upload,
+                        resource: target.resource_id,
+                        extension: &extension,
+                        progress: upload_progress(options, &target),
+                    })
+                    .await
+                    .or_else(|error| upload_err(&target, error))?,
+                Destination::Dir(dir) => dir.write(options, &target, file, &extension).await?,
+                Destination::ImagesOnly => {
+                    return upload_err(&target, "no package bucket to upload to");
+                }
+            };
 
-            *target.target = (target.property.s3_ref)(s3_bucket.to_string(), upload);
+            if let Some(cache) = cache {
+                // The cache is only an optimisation, so failing to update it is fine
+                let _ = cache.put(&upload).await;
+            }
+
+            *target.target = s3_ref(options.s3_bucket.to_string(), upload);
 
             Ok(())
         })
@@ -152,10 +619,77 @@ pub async fn process(
     Ok(())
 }
 
+/// Wait for a permit to process a package from `options.permits`.
+async fn permit<'a>(options: &'a Options<'_>) -> SemaphorePermit<'a> {
+    options
+        .permits
+        .acquire()
+        .await
+        .expect("package semaphore is never closed")
+}
+
+/// The extension of the package for `target`, e.g. `.zip`, or empty if the source has none.
+fn extension(target: &Target<'_>, strategy: PackageStrategy) -> String {
+    let Src::Local(src) = &target.src;
+    match strategy {
+        PackageStrategy::Zip | PackageStrategy::Native(_) => ".zip".to_string(),
+        PackageStrategy::File | PackageStrategy::Template => src
+            .extension()
+            .map(|ext| format!(".{}", ext.to_string_lossy()))
+            .unwrap_or_default(),
+    }
+}
+
+/// Print a progress message for `target` to STDERR, unless `quiet` is set.
+fn report(options: &Options<'_>, target: &Target<'_>, message: fmt::Arguments) {
+    if !options.quiet {
+        print_package_message(
+            options.output_format,
+            target.resource_id,
+            Some(&target.property.path.join(".")),
+            &message.to_string(),
+        );
+    }
+}
+
+/// A callback reporting upload progress for `target`, unless `quiet` is set.
+///
+/// Progress is reported when the upload is skipped, and at each quarter of the upload.
+fn upload_progress(
+    options: &Options<'_>,
+    target: &Target<'_>,
+) -> Option<Box<dyn Fn(s3::UploadProgress) + Send + Sync>> {
+    if options.quiet {
+        return None;
+    }
+
+    let format = options.output_format;
+    let resource_id = target.resource_id.to_string();
+    let property = target.property.path.join(".");
+    let print = move |message: &str| {
+        print_package_message(format, &resource_id, Some(&property), message);
+    };
+    let location = format!(
+        "s3://{}/{}",
+        options.s3_bucket,
+        options.s3_prefix.unwrap_or("")
+    );
+    let reported = AtomicU64::new(0);
+    Some(Box::new(move |progress| match progress {
+        s3::UploadProgress::Skipped => {
+            print(&format!("skipped, already present in {location}"));
+        }
+        s3::UploadProgress::Uploading { uploaded, total } => {
+            let quarter = (uploaded * 4).checked_div(total).unwrap_or(4);
+            if quarter > reported.fetch_max(quarter, Ordering::Relaxed) {
+                print(&format!("uploaded {uploaded} of {total} bytes"));
+            }
+        }
+    }))
+}
+
 async fn package_template<'a>(
-    s3_client: &'a s3::Client,
-    s3_bucket: &'a str,
-    s3_prefix: Option<&'a str>,
+    options: &'a Options<'a>,
     target: &'a Target<'a>,
 ) -> Result<File, Error> {
     // Attempt to load the source as a template
@@ -164,9 +698,15 @@ async fn package_template<'a>(
         .await
         .or_else(|error| upload_err(target, error))?;
 
+    if options.inline_includes {
+        template
+            .inline_includes()
+            .await
+            .or_else(|error| upload_err(target, error))?;
+    }
+
     // Process the template (recursive)
-    let targets = self::targets(&mut template);
-    self::process(s3_client, s3_bucket, s3_prefix, targets).await?;
+    process_template(options, &mut template).await?;
 
     let mut file = tempfile()
         .await
@@ -190,7 +730,22 @@ async fn package_template<'a>(
     Ok(file)
 }
 
-async fn package_zip(target: &Target<'_>) -> Result<File, Error> {
+async fn package_file(target: &Target<'_>) -> Result<File, Error> {
+    let Src::Local(src) = &target.src;
+    let metadata = match fs::metadata(src).await {
+        Ok(metadata) => metadata,
+        Err(error) => return upload_err(target, error),
+    };
+    if !metadata.is_file() {
+        return upload_err(target, "not a file");
+    }
+
+    File::open(src)
+        .await
+        .or_else(|error| upload_err(target, format!("couldn't open: {error}")))
+}
+
+async fn package_zip(options: &Options<'_>, target: &Target<'_>) -> Result<File, Error> {
     let Src::Local(src) = &target.src;
     let metadata = match fs::metadata(src).await {
         Ok(metadata) => metadata,
@@ -202,36 +757,88 @@ async fn package_zip(target: &Target<'_>) -> Result<File, Error> {
         .or_else(|error| upload_err(target, error))?;
     let mut writer = ZipFileWriter::new(&mut zip);
 
-    let paths = if metadata.is_file() {
-        vec![Ok(src.clone())]
+    let entries = if metadata.is_file() {
+        let entry = ZipSource {
+            path: src.clone(),
+            kind: ZipSourceKind::File {
+                mode: unix_mode(&metadata),
+            },
+        };
+        let root = src.parent().unwrap_or_else(|| Path::new(""));
+        with_root(root, vec![Ok(entry)])
     } else if metadata.is_dir() {
+        let exclude = match target.metadata.get("Exclude") {
+            Some(exclude) => match string_list(exclude) {
+                Some(exclude) => exclude,
+                None => {
+                    return upload_err(
+                        target,
+                        format!("`{METADATA_KEY}.Exclude` metadata must be a list of strings"),
+                    )
+                }
+            },
+            None => options.exclude.to_vec(),
+        };
+        let dependencies = dependencies::vendor(options, target).await?;
         let path = src.clone();
-        tokio::task::spawn_blocking(move || scandir(&path))
-            .await
-            .or_else(|error| upload_err(target, format!("couldn't read: {error}")))?
+        let symlinks = options.symlinks;
+        let entries = tokio::task::spawn_blocking(move || {
+            let ignore = ignore_matcher(&path, &exclude)?;
+            let mut entries = with_root(&path, scandir(&path, &ignore, symlinks));
+            if let Some(dependencies) = dependencies {
+                // Vendored dependencies are added to the root of the package, unfiltered
+                let ignore = Gitignore::empty();
+                entries.extend(with_root(
+                    &dependencies,
+                    scandir(&dependencies, &ignore, symlinks),
+                ));
+            }
+            Ok::<_, ignore::Error>(entries)
+        })
+        .await
+        .or_else(|error| upload_err(target, format!("couldn't read: {error}")))?
+        .or_else(|error| upload_err(target, format!("invalid exclude pattern: {error}")))?;
+        entries
     } else {
         return upload_err(target, "not a file or directory");
     };
 
-    for path in paths {
-        let path = path.or_else(|error| upload_err(target, format!("couldn't read: {error}")))?;
+    // Entries are sorted by name, since `read_dir` order varies between filesystems. If a
+    // vendored dependency has the same name as a source file, the source file takes precedence.
+    let mut entries = entries
+        .into_iter()
+        .collect::<io::Result<Vec<_>>>()
+        .or_else(|error| upload_err(target, format!("couldn't read: {error}")))?;
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    entries.dedup_by(|(a, _), (b, _)| a == b);
+
+    for (file_name, entry) in entries {
+        // Fixed compression and modification dates ensure identical inputs give identical zips
+        let builder = ZipEntryBuilder::new(file_name, Compression::Deflate)
+            .last_modification_date(dos_epoch());
+
+        let mode = match entry.kind {
+            ZipSourceKind::File { mode } => mode,
+            ZipSourceKind::Symlink { target: link } => {
+                // Symlinks are stored with the link target as their content
+                writer
+                    .write_entry_whole(
+                        builder.unix_permissions(SYMLINK_MODE),
+                        link.to_string_lossy().as_bytes(),
+                    )
+                    .await
+                    .or_else(|error| upload_err(target, format!("couldn't write: {error}")))?;
+                continue;
+            }
+        };
 
-        let file_name = path
-            .strip_prefix(src)
-            .expect("file must have file name")
-            .to_string_lossy()
-            .into_owned();
         let mut entry_writer = writer
-            .write_entry_stream(
-                ZipEntryBuilder::new(file_name, Compression::Deflate)
-                    .last_modification_date(DateTime::<Utc>::MIN_UTC)
-                    .build(),
-            )
+            .write_entry_stream(builder.unix_permissions(mode))
             .await
             .or_else(|error| upload_err(target, format!("couldn't write: {error}",)))?;
 
         let mut file = io::BufReader::new(
-            File::open(path)
+            File::open(entry.path)
                 .await
                 .or_else(|error| upload_err(target, format!("couldn't open: {error}")))?,
         );
@@ -255,7 +862,60 @@ async fn package_zip(target: &Target<'_>) -> Result<File, Error> {
     Ok(zip)
 }
 
-fn scandir(path: &Path) -> Vec<io::Result<PathBuf>> {
+/// Pair each entry with its zip entry name, relative to `root`.
+fn with_root(
+    root: &Path,
+    entries: Vec<io::Result<ZipSource>>,
+) -> Vec<io::Result<(String, ZipSource)>> {
+    entries
+        .into_iter()
+        .map(|entry| entry.map(|entry| (zip_entry_name(&entry.path, root), entry)))
+        .collect()
+}
+
+/// The name of the zip entry for `path`, relative to `root` and with `/` separators.
+fn zip_entry_name(path: &Path, root: &Path) -> String {
+    path.strip_prefix(root)
+        .expect("entry must be within root")
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Build a matcher for paths to exclude from a zipped package rooted at `root`.
+///
+/// This combines the `.cfnignore` file in `root` (if any) with `patterns`. The `.cfnignore` file
+/// itself is excluded by default.
+fn ignore_matcher(root: &Path, patterns: &[String]) -> Result<Gitignore, ignore::Error> {
+    let mut builder = GitignoreBuilder::new(root);
+    builder.add_line(None, &format!("/{IGNORE_FILE}"))?;
+
+    let ignore_file = root.join(IGNORE_FILE);
+    if ignore_file.is_file() {
+        if let Some(error) = builder.add(ignore_file) {
+            return Err(error);
+        }
+    }
+    for pattern in patterns {
+        builder.add_line(None, pattern)?;
+    }
+
+    builder.build()
+}
+
+/// A path to be added to a zipped package.
+struct ZipSource {
+    path: PathBuf,
+    kind: ZipSourceKind,
+}
+
+enum ZipSourceKind {
+    File { mode: u16 },
+    Symlink { target: PathBuf },
+}
+
+fn scandir(path: &Path, ignore: &Gitignore, symlinks: SymlinkPolicy) -> Vec<io::Result<ZipSource>> {
     let entries = match std::fs::read_dir(path) {
         Ok(entries) => entries,
         Err(error) => return vec![Err(error)],
@@ -270,7 +930,7 @@ fn scandir(path: &Path) -> Vec<io::Result<PathBuf>> {
             }
         };
 
-        let metadata = match std::fs::metadata(&entry_path) {
+        let metadata = match std::fs::symlink_metadata(&entry_path) {
             Ok(metadata) => metadata,
             Err(error) => {
                 paths.push(Err(error));
@@ -278,16 +938,98 @@ fn scandir(path: &Path) -> Vec<io::Result<PathBuf>> {
             }
         };
 
+        let metadata = if metadata.file_type().is_symlink() {
+            match symlinks {
+                SymlinkPolicy::Follow => match std::fs::metadata(&entry_path) {
+                    Ok(metadata) => metadata,
+                    Err(error) => {
+                        paths.push(Err(error));
+                        return paths;
+                    }
+                },
+                SymlinkPolicy::Preserve => {
+                    if !ignore.matched(&entry_path, false).is_ignore() {
+                        paths.push(std::fs::read_link(&entry_path).map(|target| ZipSource {
+                            path: entry_path,
+                            kind: ZipSourceKind::Symlink { target },
+                        }));
+                    }
+                    return paths;
+                }
+                SymlinkPolicy::Error => {
+                    if !ignore.matched(&entry_path, false).is_ignore() {
+                        paths.push(Err(io::Error::other(format!(
+                            "`{}` is a symbolic link",
+                            entry_path.display()
+                        ))));
+                    }
+                    return paths;
+                }
+            }
+        } else {
+            metadata
+        };
+
+        if ignore.matched(&entry_path, metadata.is_dir()).is_ignore() {
+            return paths;
+        }
+
         if metadata.is_dir() {
-            paths.extend(scandir(&entry_path));
+            paths.extend(scandir(&entry_path, ignore, symlinks));
         } else {
-            paths.push(Ok(entry_path));
+            paths.push(Ok(ZipSource {
+                path: entry_path,
+                kind: ZipSourceKind::File {
+                    mode: unix_mode(&metadata),
+                },
+            }));
         }
 
         paths
     })
 }
 
+/// The Unix mode (file type and permissions) to record for a file in a zipped package.
+///
+/// Like git, only the executable bit is significant. This avoids differences in umask between
+/// machines changing the zip (and its content hash).
+#[cfg(unix)]
+fn unix_mode(metadata: &std::fs::Metadata) -> u16 {
+    use std::os::unix::fs::PermissionsExt;
+
+    if metadata.permissions().mode() & 0o111 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+
+/// The Unix mode (file type and permissions) to record for a file in a zipped package.
+#[cfg(not(unix))]
+fn unix_mode(_metadata: &std::fs::Metadata) -> u16 {
+    0o100644
+}
+
+/// Read a YAML value as a list of strings.
+fn string_list(value: &YamlValue) -> Option<Vec<String>> {
+    value
+        .as_sequence()?
+        .iter()
+        .map(|item| item.as_str().map(str::to_string))
+        .collect()
+}
+
+/// The directory for cached packaging artifacts, `$XDG_CACHE_HOME/cloudformatious`.
+///
+/// This falls back to `$HOME/.cache/cloudformatious` if `XDG_CACHE_HOME` isn't set.
+fn cache_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(cache_home) if !cache_home.is_empty() => PathBuf::from(cache_home),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("cloudformatious"))
+}
+
 async fn tempfile() -> Result<File, Error> {
     let file = tokio::task::spawn_blocking(tempfile::tempfile)
         .await
@@ -304,3 +1046,190 @@ fn upload_err<T>(target: &Target, error: impl fmt::Display) -> Result<T, Error>
         target.resource_id
     )))
 }
+
+#[test]
+fn test_scandir_exclude() {
+    let root = tempfile::tempdir().unwrap();
+    for path in [
+        "index.js",
+        ".env",
+        "node_modules/.cache/x",
+        "node_modules/dep/index.js",
+    ] {
+        let path = root.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+    std::fs::write(root.path().join(IGNORE_FILE), ".env\n").unwrap();
+
+    let ignore = ignore_matcher(root.path(), &["node_modules/.cache".to_string()]).unwrap();
+    let mut paths: Vec<_> = scandir(root.path(), &ignore, SymlinkPolicy::Follow)
+        .into_iter()
+        .map(|entry| {
+            entry
+                .unwrap()
+                .path
+                .strip_prefix(root.path())
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    paths.sort();
+
+    assert_eq!(paths, ["index.js", "node_modules/dep/index.js"]);
+}
+
+#[test]
+fn test_zip_entry_name() {
+    let root = Path::new("src");
+    assert_eq!(zip_entry_name(&root.join("index.js"), root), "index.js");
+    assert_eq!(
+        zip_entry_name(&root.join("lib").join("util.js"), root),
+        "lib/util.js"
+    );
+}
+
+#[tokio::test]
+async fn test_targets_image() {
+    let mut template = Template::open(PathBuf::from("test/fixtures/image.yaml"))
+        .await
+        .unwrap();
+    let targets: Vec<_> = targets(&mut template)
+        .await
+        .into_iter()
+        .map(|target| {
+            let packaging = match target.property.packaging {
+                Packaging::S3 { strategy, .. } => format!("{strategy:?}"),
+                Packaging::Image => "Image".to_string(),
+            };
+            (
+                target.resource_id,
+                target.property.path.join("."),
+                packaging,
+            )
+        })
+        .collect();
+    assert_eq!(
+        targets,
+        [
+            (
+                "ImageFunction",
+                "Code.ImageUri".to_string(),
+                "Image".to_string()
+            ),
+            ("ZipFunction", "Code".to_string(), "Zip".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_target_kinds() {
+    let mut template = Template::open(PathBuf::from("test/fixtures/image-only.yaml"))
+        .await
+        .unwrap();
+    let kinds = target_kinds(&mut template).await;
+    assert!(!kinds.s3 && kinds.images);
+
+    let mut template = Template::open(PathBuf::from("test/fixtures/image.yaml"))
+        .await
+        .unwrap();
+    let kinds = target_kinds(&mut template).await;
+    assert!(kinds.s3 && kinds.images);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_package_zip_reproducible() {
+    use std::{os::unix::fs::PermissionsExt, time::SystemTime};
+
+    use async_zip::{read::mem::ZipFileReader, ZipEntryExt};
+    use tokio::io::AsyncReadExt;
+
+    let root = tempfile::tempdir().unwrap();
+    let src = root.path().join("src");
+    std::fs::create_dir_all(src.join("lib")).unwrap();
+    std::fs::write(src.join("lib/util.js"), "exports.util = () => {};").unwrap();
+    std::fs::write(src.join("bootstrap"), "#!/bin/sh").unwrap();
+    std::fs::set_permissions(
+        src.join("bootstrap"),
+        std::fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+
+    let dir = LocalDir::new(&root.path().join("packages"), None);
+    let upload = s3::UploadConfig {
+        multipart_threshold: u64::MAX,
+        multipart_part_size: 0,
+        sse: None,
+        kms_key_id: None,
+        acl: None,
+        tags: Vec::new(),
+        key_hash: s3::KeyHash::Md5,
+        key_template: s3::KeyTemplate::default(),
+    };
+    let options = Options {
+        destination: Destination::Dir(&dir),
+        s3_bucket: "bucket",
+        s3_prefix: None,
+        upload: &upload,
+        image_repository: None,
+        inline_includes: false,
+        exclude: &[],
+        symlinks: SymlinkPolicy::Follow,
+        dependencies: false,
+        permits: Semaphore::new(1),
+        quiet: true,
+        output_format: OutputFormat::Text,
+    };
+    let mut value = YamlValue::Null;
+    let target = Target {
+        resource_id: "Function",
+        property: PACKAGEABLE_PROPERTIES
+            .iter()
+            .find(|prop| prop.resource_type == "AWS::Lambda::Function" && prop.path == ["Code"])
+            .unwrap(),
+        target: &mut value,
+        src: Src::Local(src.clone()),
+        metadata: YamlValue::Null,
+        architecture: None,
+    };
+
+    let mut zips = Vec::new();
+    for modified in [SystemTime::UNIX_EPOCH, SystemTime::now()] {
+        for path in ["bootstrap", "lib/util.js"] {
+            std::fs::File::options()
+                .write(true)
+                .open(src.join(path))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        let mut zip = Vec::new();
+        package_zip(&options, &target)
+            .await
+            .unwrap()
+            .read_to_end(&mut zip)
+            .await
+            .unwrap();
+        zips.push(zip);
+    }
+    assert_eq!(zips[0], zips[1]);
+
+    let reader = ZipFileReader::new(&zips[0]).await.unwrap();
+    let entries: Vec<_> = reader
+        .entries()
+        .into_iter()
+        .map(|entry| {
+            assert_eq!(*entry.last_modification_date(), dos_epoch());
+            (entry.filename().to_string(), entry.unix_permissions())
+        })
+        .collect();
+    assert_eq!(
+        entries,
+        [
+            ("bootstrap".to_string(), Some(0o100755)),
+            ("lib/util.js".to_string(), Some(0o100644)),
+        ]
+    );
+}
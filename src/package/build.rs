@@ -0,0 +1,141 @@
+use std::process::Stdio;
+
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
+    process::Command,
+};
+
+use super::{upload_err, Options, Src, Target, METADATA_KEY};
+use crate::{fmt::print_package_message, Error};
+
+/// Run the build command declared in a resource's `Metadata.Cloudformatious.Build`, if any.
+///
+/// The command is run with a shell in the source directory (or the directory containing the
+/// source, if it's a file). If `Metadata.Cloudformatious.Output` is also set, the target's source
+/// is replaced with that path, relative to the build directory.
+///
+/// Output from the command is streamed to STDERR, prefixed with the resource ID, unless `quiet`
+/// is set.
+pub(super) async fn run(options: &Options<'_>, target: &mut Target<'_>) -> Result<(), Error> {
+    let Some(build) = target.metadata.get("Build") else {
+        return Ok(());
+    };
+    let Some(build) = build.as_str().map(str::to_string) else {
+        return upload_err(
+            target,
+            format!("`{METADATA_KEY}.Build` metadata must be a string"),
+        );
+    };
+    let output = match target.metadata.get("Output") {
+        Some(output) => match output.as_str() {
+            Some(output) => Some(output.to_string()),
+            None => {
+                return upload_err(
+                    target,
+                    format!("`{METADATA_KEY}.Output` metadata must be a string"),
+                )
+            }
+        },
+        None => None,
+    };
+
+    let Src::Local(src) = &target.src;
+    let dir = match fs::metadata(src).await {
+        Ok(metadata) if metadata.is_dir() => src.clone(),
+        Ok(_) => src
+            .parent()
+            .expect("file path must have a parent")
+            .to_path_buf(),
+        Err(error) => return upload_err(target, error),
+    };
+
+    let mut command = shell(&build);
+    command.current_dir(&dir);
+    run_command(options, target, &format!("build `{build}`"), command, false).await?;
+
+    if let Some(output) = output {
+        target.src = Src::Local(dir.join(output));
+    }
+
+    Ok(())
+}
+
+/// Run `command` to completion, failing the target if it exits unsuccessfully.
+///
+/// STDERR is streamed with the resource ID prefix, unless `quiet` is set. STDOUT is likewise
+/// streamed, unless `capture_stdout` is set, in which case it's returned instead.
+pub(super) async fn run_command(
+    options: &Options<'_>,
+    target: &Target<'_>,
+    description: &str,
+    mut command: Command,
+    capture_stdout: bool,
+) -> Result<String, Error> {
+    let mut child = match command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(error) => return upload_err(target, format!("couldn't run {description}: {error}")),
+    };
+
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let prefix = target.resource_id;
+    let mut captured = String::new();
+    let status = tokio::try_join!(
+        child.wait(),
+        async {
+            if capture_stdout {
+                stdout.read_to_string(&mut captured).await.map(|_| ())
+            } else {
+                stream_lines(options, prefix, stdout).await
+            }
+        },
+        stream_lines(options, prefix, stderr),
+    );
+    let status = match status {
+        Ok((status, (), ())) => status,
+        Err(error) => return upload_err(target, format!("couldn't run {description}: {error}")),
+    };
+    if !status.success() {
+        return upload_err(target, format!("{description} failed with {status}"));
+    }
+
+    Ok(captured)
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Print each line from `reader` to STDERR for resource `prefix`, unless `quiet` is set.
+///
+/// The reader is always drained, so the child process doesn't block on a full pipe.
+async fn stream_lines(
+    options: &Options<'_>,
+    prefix: &str,
+    reader: impl AsyncRead + Unpin,
+) -> std::io::Result<()> {
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if !options.quiet {
+            print_package_message(options.output_format, prefix, None, &line);
+        }
+    }
+    Ok(())
+}
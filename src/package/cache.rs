@@ -0,0 +1,139 @@
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde_json::json;
+use tokio::fs;
+
+use super::{
+    cache_dir, extension, Destination, Options, PackageStrategy, Src, SymlinkPolicy, Target,
+};
+use crate::{s3, Error};
+
+/// A cache of previously uploaded packages, keyed by a fingerprint of their sources.
+///
+/// The fingerprint is built from the paths, sizes and modification times of the source files,
+/// along with any settings that affect packaging or the rendered key, so it's cheap to compute
+/// without reading any file contents. Each entry records the content hash and S3 key of the
+/// resulting package.
+///
+/// The cache is an optimisation only, so failures to read or write it are ignored.
+pub(super) struct Entry {
+    path: PathBuf,
+}
+
+impl Entry {
+    /// The cache entry for `target`, if it can be cached.
+    ///
+    /// Targets with build steps, native builders, or nested templates aren't cached, since their
+    /// output depends on more than the files in their source. Nothing is cached when packaging to
+    /// a local directory.
+    pub(super) async fn for_target(
+        options: &Options<'_>,
+        target: &Target<'_>,
+        strategy: PackageStrategy,
+    ) -> Option<Self> {
+        if !matches!(strategy, PackageStrategy::File | PackageStrategy::Zip)
+            || target.metadata.get("Build").is_some()
+            || !matches!(options.destination, Destination::S3(_))
+        {
+            return None;
+        }
+
+        let Src::Local(src) = &target.src;
+        let src = src.clone();
+        let follow = matches!(options.symlinks, SymlinkPolicy::Follow);
+        let files = tokio::task::spawn_blocking(move || {
+            let mut files = Vec::new();
+            fingerprint_files(&src, follow, &mut files).ok()?;
+            Some(files)
+        })
+        .await
+        .ok()??;
+
+        let fingerprint = json!({
+            "bucket": options.s3_bucket,
+            "prefix": options.s3_prefix,
+            "resourceId": target.resource_id,
+            "extension": extension(target, strategy),
+            "keyHash": format!("{:?}", options.upload.key_hash),
+            "keyTemplate": options.upload.key_template.to_string(),
+            "strategy": format!("{strategy:?}"),
+            "exclude": options.exclude,
+            "symlinks": format!("{:?}", options.symlinks),
+            "dependencies": options.dependencies,
+            "metadata": serde_json::to_value(&target.metadata).ok()?,
+            "files": files,
+        });
+        let fingerprint = md5::compute(fingerprint.to_string());
+
+        let path = cache_dir()?
+            .join("packages")
+            .join(format!("{:x}", fingerprint));
+        Some(Self { path })
+    }
+
+    /// Look up a previous upload, checking that it still exists in S3.
+    pub(super) async fn get(&self, options: &Options<'_>) -> Option<s3::UploadOutput> {
+        let entry = fs::read(&self.path).await.ok()?;
+        let entry: serde_json::Value = serde_json::from_slice(&entry).ok()?;
+        let key = entry["key"].as_str()?.to_string();
+        let hash = entry["hash"].as_str()?.to_string();
+
+        // The object may have been removed since it was cached, in which case we upload again
+        let Destination::S3(client) = options.destination else {
+            return None;
+        };
+        let uri = client
+            .exists(options.s3_bucket, &key, options.upload)
+            .await
+            .ok()??;
+        Some(s3::UploadOutput { uri, key, hash })
+    }
+
+    /// Record an upload for future lookups.
+    pub(super) async fn put(&self, upload: &s3::UploadOutput) -> Result<(), Error> {
+        let entry = json!({ "key": upload.key, "hash": upload.hash });
+        let dir = self.path.parent().expect("cache entry must have a parent");
+        fs::create_dir_all(dir)
+            .await
+            .and(fs::write(&self.path, entry.to_string()).await)
+            .map_err(|error| Error::other(format!("couldn't write package cache: {error}")))
+    }
+}
+
+/// Collect the path, size and modification time of `path` and (if it's a directory) everything
+/// beneath it, in a deterministic order.
+fn fingerprint_files(
+    path: &Path,
+    follow: bool,
+    files: &mut Vec<serde_json::Value>,
+) -> std::io::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        let link = std::fs::read_link(path)?;
+        files.push(json!([path, "symlink", link]));
+        if !follow {
+            return Ok(());
+        }
+    }
+
+    let metadata = std::fs::metadata(path)?;
+    if metadata.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            fingerprint_files(&entry, follow, files)?;
+        }
+    } else {
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |mtime| mtime.as_nanos());
+        files.push(json!([path, metadata.len(), mtime.to_string()]));
+    }
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use std::path::PathBuf;
+
+use tokio::{fs, process::Command};
+
+use super::{build::run_command, upload_err, Options, Src, Target, METADATA_KEY};
+use crate::Error;
+
+/// A dependency manifest that can be vendored into a package.
+#[derive(Clone, Copy, Debug)]
+enum Manifest {
+    /// `requirements.txt`, installed with `pip install --target`.
+    Pip,
+
+    /// `package.json` (and `package-lock.json`), installed with `npm ci --omit=dev`.
+    Npm,
+}
+
+impl Manifest {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Pip => "pip",
+            Self::Npm => "npm",
+        }
+    }
+
+    /// The files whose content determines the installed dependencies.
+    fn lockfiles(self) -> &'static [&'static str] {
+        match self {
+            Self::Pip => &["requirements.txt"],
+            Self::Npm => &["package.json", "package-lock.json"],
+        }
+    }
+}
+
+/// Install the dependencies of the target's source directory into a staging directory, if
+/// enabled.
+///
+/// Vendoring is enabled by `Metadata.Cloudformatious.Dependencies`, or `options.dependencies` if
+/// that's not set. The staging directory is cached, keyed by a hash of the lockfiles, so it's
+/// only populated when dependencies change. Its contents should be added to the package alongside
+/// the source directory.
+pub(super) async fn vendor(
+    options: &Options<'_>,
+    target: &Target<'_>,
+) -> Result<Option<PathBuf>, Error> {
+    let enabled = match target.metadata.get("Dependencies") {
+        Some(enabled) => match enabled.as_bool() {
+            Some(enabled) => enabled,
+            None => {
+                return upload_err(
+                    target,
+                    format!("`{METADATA_KEY}.Dependencies` metadata must be a boolean"),
+                )
+            }
+        },
+        None => options.dependencies,
+    };
+    if !enabled {
+        return Ok(None);
+    }
+
+    let Src::Local(src) = &target.src;
+    let manifest = if fs::metadata(src.join("requirements.txt")).await.is_ok() {
+        Manifest::Pip
+    } else if fs::metadata(src.join("package.json")).await.is_ok() {
+        Manifest::Npm
+    } else {
+        return Ok(None);
+    };
+
+    let mut hash = md5::Context::new();
+    for lockfile in manifest.lockfiles() {
+        let content = fs::read(src.join(lockfile))
+            .await
+            .or_else(|error| upload_err(target, format!("couldn't read `{lockfile}`: {error}")))?;
+        hash.consume(lockfile.as_bytes());
+        hash.consume(content);
+    }
+
+    let Some(cache_dir) = super::cache_dir() else {
+        return upload_err(target, "couldn't determine a cache directory");
+    };
+    let cache_dir = cache_dir.join("dependencies");
+    let staging = cache_dir.join(format!("{}-{:x}", manifest.name(), hash.compute()));
+    if fs::metadata(&staging).await.is_ok() {
+        return Ok(Some(staging));
+    }
+
+    // Install into a scratch directory first, so a failed install doesn't poison the cache
+    fs::create_dir_all(&cache_dir)
+        .await
+        .or_else(|error| upload_err(target, format!("couldn't create cache: {error}")))?;
+    let scratch = tokio::task::spawn_blocking(move || tempfile::tempdir_in(cache_dir))
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+        .or_else(|error| upload_err(target, format!("couldn't create temporary dir: {error}")))?;
+
+    let command = match manifest {
+        Manifest::Pip => {
+            let mut command = Command::new("pip");
+            command
+                .args(["install", "--disable-pip-version-check", "--no-compile"])
+                .arg("--requirement")
+                .arg(src.join("requirements.txt"))
+                .arg("--target")
+                .arg(scratch.path());
+            command
+        }
+        Manifest::Npm => {
+            for lockfile in manifest.lockfiles() {
+                fs::copy(src.join(lockfile), scratch.path().join(lockfile))
+                    .await
+                    .or_else(|error| {
+                        upload_err(target, format!("couldn't copy `{lockfile}`: {error}"))
+                    })?;
+            }
+            let mut command = Command::new("npm");
+            command
+                .current_dir(scratch.path())
+                .args(["ci", "--omit=dev"]);
+            command
+        }
+    };
+    let description = format!("`{}` dependency install", manifest.name());
+    run_command(options, target, &description, command, false).await?;
+
+    // Only the installed dependencies are kept, the source directory provides the manifests
+    if let Manifest::Npm = manifest {
+        for lockfile in manifest.lockfiles() {
+            fs::remove_file(scratch.path().join(lockfile))
+                .await
+                .or_else(|error| upload_err(target, format!("couldn't stage: {error}")))?;
+        }
+    }
+
+    // Another process may have populated the cache in the meantime, which is fine
+    let scratch = scratch.into_path();
+    if fs::rename(&scratch, &staging).await.is_err() {
+        let _ = fs::remove_dir_all(&scratch).await;
+        if fs::metadata(&staging).await.is_err() {
+            return upload_err(target, "couldn't populate dependency cache");
+        }
+    }
+
+    Ok(Some(staging))
+}
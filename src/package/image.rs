@@ -0,0 +1,75 @@
+use tokio::{fs, process::Command};
+
+use super::{build::run_command, native::Architecture, upload_err, Options, Src, Target};
+use crate::Error;
+
+/// Build the container image at the target's source and push it to `--image-repository`.
+///
+/// The source may be a Dockerfile, in which case its directory is the build context, or a
+/// directory containing a `Dockerfile`. The image is tagged with its image ID, so unchanged images
+/// are not pushed again, and the returned URI references the pushed image by digest.
+pub(super) async fn package_image(
+    options: &Options<'_>,
+    target: &Target<'_>,
+) -> Result<String, Error> {
+    let Some(repository) = options.image_repository else {
+        return upload_err(target, "`--image-repository` is required to package images");
+    };
+    let architecture = Architecture::of(target)?;
+
+    let Src::Local(src) = &target.src;
+    let (dockerfile, context) = match fs::metadata(src).await {
+        Ok(metadata) if metadata.is_dir() => (src.join("Dockerfile"), src.as_path()),
+        Ok(_) => (
+            src.clone(),
+            src.parent().expect("file path must have a parent"),
+        ),
+        Err(error) => return upload_err(target, error),
+    };
+
+    let mut command = Command::new("docker");
+    command
+        .args([
+            "build",
+            "--quiet",
+            "--platform",
+            architecture.docker_platform(),
+        ])
+        .arg("--file")
+        .arg(&dockerfile)
+        .arg(context);
+    let image_id = run_command(options, target, "`docker build`", command, true).await?;
+    let image_id = image_id.trim();
+
+    // The image ID is a digest of the image config, so it's a stable content-addressed tag
+    let tag = image_id.strip_prefix("sha256:").unwrap_or(image_id);
+    let image = format!("{repository}:{tag}");
+
+    let mut command = Command::new("docker");
+    command.args(["tag", image_id, &image]);
+    run_command(options, target, "`docker tag`", command, false).await?;
+
+    let mut command = Command::new("docker");
+    command.args(["push", "--quiet", &image]);
+    run_command(options, target, "`docker push`", command, true).await?;
+
+    let mut command = Command::new("docker");
+    command.args([
+        "image",
+        "inspect",
+        "--format",
+        "{{range .RepoDigests}}{{println .}}{{end}}",
+        &image,
+    ]);
+    let digests = run_command(options, target, "`docker image inspect`", command, true).await?;
+    match digests
+        .lines()
+        .find(|digest| digest.starts_with(&format!("{repository}@")))
+    {
+        Some(digest) => Ok(digest.to_string()),
+        None => upload_err(
+            target,
+            format!("couldn't find the digest of `{image}` after pushing"),
+        ),
+    }
+}
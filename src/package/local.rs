@@ -0,0 +1,326 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use aws_types::region::Region;
+use serde_json::{json, Value as JsonValue};
+use tokio::fs::{self, File};
+
+use super::{upload_err, Options, Src, Target};
+use crate::{s3, Error};
+
+/// The name of the manifest written to a package directory.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// A local directory to write packages to, instead of uploading them to S3.
+///
+/// Packages are written to the same content-addressed keys they would be uploaded to, relative to
+/// the directory, and recorded in a manifest so they can be uploaded later.
+pub struct LocalDir {
+    path: PathBuf,
+    region: Option<String>,
+    artifacts: Mutex<Vec<Artifact>>,
+}
+
+impl LocalDir {
+    pub fn new(path: &Path, region: Option<&Region>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            region: region.map(Region::to_string),
+            artifacts: Mutex::default(),
+        }
+    }
+
+    /// Write the package for `target` into the directory, unless it's already present.
+    pub(super) async fn write(
+        &self,
+        options: &Options<'_>,
+        target: &Target<'_>,
+        file: File,
+        extension: &str,
+    ) -> Result<s3::UploadOutput, Error> {
+        let content = s3::hash_file(file, options.upload.key_hash).await?;
+        let key = options.upload.key_template.render(
+            options.s3_prefix.unwrap_or(""),
+            target.resource_id,
+            &content.hash,
+            extension,
+        );
+
+        let path = self.path.join(&key);
+        if fs::metadata(&path).await.is_ok() {
+            super::report(
+                options,
+                target,
+                format_args!("skipped, already present in {}", path.display()),
+            );
+        } else {
+            let mut file = content.file;
+            async {
+                fs::create_dir_all(path.parent().expect("package path must have a parent")).await?;
+                let mut output = File::create(&path).await?;
+                tokio::io::copy(&mut file, &mut output).await
+            }
+            .await
+            .or_else(|error| {
+                upload_err(
+                    target,
+                    format!("couldn't write {}: {error}", path.display()),
+                )
+            })?;
+            super::report(options, target, format_args!("wrote {}", path.display()));
+        }
+
+        let Src::Local(src) = &target.src;
+        self.artifacts.lock().unwrap().push(Artifact {
+            resource_id: target.resource_id.to_string(),
+            property: target.property.path.join("."),
+            source: src.clone(),
+            key: key.clone(),
+            hash: content.hash.clone(),
+        });
+
+        // The URI the package will have once it's uploaded
+        let bucket = options.s3_bucket;
+        let uri = match &self.region {
+            Some(region) => format!("https://{bucket}.s3.{region}.amazonaws.com/{key}"),
+            None => format!("https://{bucket}.s3.amazonaws.com/{key}"),
+        };
+        Ok(s3::UploadOutput {
+            uri,
+            key,
+            hash: content.hash,
+        })
+    }
+
+    /// Write the manifest of packages written to the directory.
+    ///
+    /// Artifacts are sorted, since packages are written concurrently.
+    pub async fn write_manifest(&self, bucket: Option<&str>) -> Result<(), Error> {
+        let mut artifacts = self.artifacts.lock().unwrap().clone();
+        artifacts.sort_by(|a, b| (&a.resource_id, &a.property).cmp(&(&b.resource_id, &b.property)));
+        let manifest = Manifest {
+            bucket: bucket.map(str::to_string),
+            region: self.region.clone(),
+            artifacts,
+        };
+
+        let path = self.path.join(MANIFEST_FILE);
+        async {
+            fs::create_dir_all(&self.path).await?;
+            fs::write(&path, serde_json::to_string_pretty(&manifest.to_json())?).await
+        }
+        .await
+        .map_err(|error| {
+            Error::other(format!(
+                "couldn't write package manifest `{}`: {error}",
+                path.display()
+            ))
+        })
+    }
+}
+
+/// The manifest of a package directory, listing the packages written to it.
+#[derive(Debug, PartialEq)]
+pub struct Manifest {
+    /// The bucket the packages are referenced in, and should be uploaded to.
+    pub bucket: Option<String>,
+
+    /// The region whose endpoint packages are referenced with, if any.
+    pub region: Option<String>,
+
+    pub artifacts: Vec<Artifact>,
+}
+
+/// A package written to a package directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Artifact {
+    pub resource_id: String,
+    pub property: String,
+    pub source: PathBuf,
+
+    /// The key of the package, both relative to the directory and in the bucket.
+    pub key: String,
+    pub hash: String,
+}
+
+impl Manifest {
+    /// Read the manifest in the package directory at `path`.
+    pub async fn read(path: &Path) -> Result<Self, Error> {
+        let path = path.join(MANIFEST_FILE);
+        fs::read(&path)
+            .await
+            .map_err(|error| error.to_string())
+            .and_then(|manifest| {
+                serde_json::from_slice(&manifest).map_err(|error| error.to_string())
+            })
+            .and_then(|manifest| Self::from_json(&manifest))
+            .map_err(|error| {
+                Error::other(format!(
+                    "couldn't read package manifest `{}`: {error}",
+                    path.display()
+                ))
+            })
+    }
+
+    fn to_json(&self) -> JsonValue {
+        json!({
+            "bucket": self.bucket,
+            "region": self.region,
+            "artifacts": self.artifacts.iter().map(|artifact| json!({
+                "resourceId": artifact.resource_id,
+                "property": artifact.property,
+                "source": artifact.source.to_string_lossy(),
+                "key": artifact.key,
+                "hash": artifact.hash,
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    fn from_json(manifest: &JsonValue) -> Result<Self, String> {
+        let string = |value: &JsonValue, field: &str| {
+            value[field]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("`{field}` must be a string"))
+        };
+        let artifacts = manifest["artifacts"]
+            .as_array()
+            .ok_or("`artifacts` must be an array")?
+            .iter()
+            .map(|artifact| {
+                Ok(Artifact {
+                    resource_id: string(artifact, "resourceId")?,
+                    property: string(artifact, "property")?,
+                    source: string(artifact, "source")?.into(),
+                    key: string(artifact, "key")?,
+                    hash: string(artifact, "hash")?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            bucket: manifest["bucket"].as_str().map(str::to_string),
+            region: manifest["region"].as_str().map(str::to_string),
+            artifacts,
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_manifest() {
+    use serde_yaml::Value as YamlValue;
+    use tokio::sync::Semaphore;
+
+    use super::{Destination, OutputFormat, SymlinkPolicy, PACKAGEABLE_PROPERTIES};
+
+    let root = tempfile::tempdir().unwrap();
+    let src = root.path().join("index.js");
+    std::fs::write(&src, "exports.handler = () => {};").unwrap();
+
+    let dir = LocalDir::new(
+        &root.path().join("packages"),
+        Some(&Region::new("eu-west-1")),
+    );
+    let upload = s3::UploadConfig {
+        multipart_threshold: u64::MAX,
+        multipart_part_size: 0,
+        sse: None,
+        kms_key_id: None,
+        acl: None,
+        tags: Vec::new(),
+        key_hash: s3::KeyHash::Md5,
+        key_template: "{prefix}/{resource}/{hash}{ext}".parse().unwrap(),
+    };
+    let options = Options {
+        destination: Destination::Dir(&dir),
+        s3_bucket: "bucket",
+        s3_prefix: Some("pkg"),
+        upload: &upload,
+        image_repository: None,
+        inline_includes: false,
+        exclude: &[],
+        symlinks: SymlinkPolicy::Follow,
+        dependencies: false,
+        permits: Semaphore::new(1),
+        quiet: true,
+        output_format: OutputFormat::Text,
+    };
+    let mut value = YamlValue::Null;
+    let target = Target {
+        resource_id: "Function",
+        property: PACKAGEABLE_PROPERTIES
+            .iter()
+            .find(|prop| prop.resource_type == "AWS::Lambda::Function" && prop.path == ["Code"])
+            .unwrap(),
+        target: &mut value,
+        src: Src::Local(src.clone()),
+        metadata: YamlValue::Null,
+        architecture: None,
+    };
+
+    let file = File::open(&src).await.unwrap();
+    let output = dir.write(&options, &target, file, ".js").await.unwrap();
+    let key = format!("pkg/Function/{}.js", output.hash);
+    assert_eq!(output.key, key);
+    assert_eq!(
+        output.uri,
+        format!("https://bucket.s3.eu-west-1.amazonaws.com/{key}")
+    );
+    assert!(root.path().join("packages").join(&key).is_file());
+
+    dir.write_manifest(Some("bucket")).await.unwrap();
+    let manifest = Manifest::read(&root.path().join("packages")).await.unwrap();
+    assert_eq!(
+        manifest,
+        Manifest {
+            bucket: Some("bucket".to_string()),
+            region: Some("eu-west-1".to_string()),
+            artifacts: vec![Artifact {
+                resource_id: "Function".to_string(),
+                property: "Code".to_string(),
+                source: src,
+                key,
+                hash: output.hash,
+            }],
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_images_rejected() {
+    use tokio::sync::Semaphore;
+
+    use super::{process_template, Destination, OutputFormat, SymlinkPolicy};
+    use crate::Template;
+
+    let root = tempfile::tempdir().unwrap();
+    let dir = LocalDir::new(&root.path().join("packages"), None);
+    let upload = s3::UploadConfig::default();
+    let options = Options {
+        destination: Destination::Dir(&dir),
+        s3_bucket: "bucket",
+        s3_prefix: None,
+        upload: &upload,
+        image_repository: Some("123456789012.dkr.ecr.eu-west-1.amazonaws.com/function"),
+        inline_includes: false,
+        exclude: &[],
+        symlinks: SymlinkPolicy::Follow,
+        dependencies: false,
+        permits: Semaphore::new(1),
+        quiet: true,
+        output_format: OutputFormat::Text,
+    };
+
+    let mut template = Template::open(PathBuf::from("test/fixtures/image-only.yaml"))
+        .await
+        .unwrap();
+    let error = process_template(&options, &mut template).await.unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("container images can't be packaged to a local directory"),
+        "{}",
+        error
+    );
+}
@@ -0,0 +1,180 @@
+use std::path::PathBuf;
+
+use tokio::{fs, process::Command};
+
+use super::{build::run_command, package_zip, upload_err, Options, Src, Target, METADATA_KEY};
+use crate::Error;
+
+/// A built-in builder for functions using an OS-only runtime (`provided.al2`/`provided.al2023`).
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Builder {
+    /// Build a Cargo package with `cargo build`.
+    Rust,
+
+    /// Build a Go module with `go build`.
+    Go,
+}
+
+impl Builder {
+    /// Select a builder from a resource's `Metadata.Cloudformatious.Builder`, if any.
+    pub(super) fn from_metadata(target: &Target<'_>) -> Result<Option<Self>, Error> {
+        match target.metadata.get("Builder") {
+            None => Ok(None),
+            Some(builder) => match builder.as_str() {
+                Some("rust") => Ok(Some(Self::Rust)),
+                Some("go") => Ok(Some(Self::Go)),
+                _ => upload_err(
+                    target,
+                    format!("`{METADATA_KEY}.Builder` metadata must be `rust` or `go`"),
+                ),
+            },
+        }
+    }
+}
+
+/// A function architecture, as given by the `Architectures` property.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Architecture {
+    X86_64,
+    Arm64,
+}
+
+impl Architecture {
+    /// The architecture of the target's resource, defaulting to `x86_64` like Lambda does.
+    pub(super) fn of(target: &Target<'_>) -> Result<Self, Error> {
+        match target.architecture.as_deref() {
+            None | Some("x86_64") => Ok(Self::X86_64),
+            Some("arm64") => Ok(Self::Arm64),
+            Some(architecture) => {
+                upload_err(target, format!("unsupported architecture `{architecture}`"))
+            }
+        }
+    }
+
+    pub(super) fn docker_platform(self) -> &'static str {
+        match self {
+            Self::X86_64 => "linux/amd64",
+            Self::Arm64 => "linux/arm64",
+        }
+    }
+
+    fn rust_target(self) -> &'static str {
+        match self {
+            Self::X86_64 => "x86_64-unknown-linux-musl",
+            Self::Arm64 => "aarch64-unknown-linux-musl",
+        }
+    }
+
+    fn goarch(self) -> &'static str {
+        match self {
+            Self::X86_64 => "amd64",
+            Self::Arm64 => "arm64",
+        }
+    }
+}
+
+/// Compile the package at the target's source and zip the binary as `bootstrap`.
+pub(super) async fn package_native(
+    options: &Options<'_>,
+    target: &mut Target<'_>,
+    builder: Builder,
+) -> Result<fs::File, Error> {
+    let architecture = Architecture::of(target)?;
+
+    let out_dir = tokio::task::spawn_blocking(tempfile::tempdir)
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+        .or_else(|error| upload_err(target, format!("couldn't create temporary dir: {error}")))?;
+    let bootstrap = out_dir.path().join("bootstrap");
+
+    match builder {
+        Builder::Rust => {
+            let binary = build_rust(options, target, architecture).await?;
+            fs::copy(&binary, &bootstrap)
+                .await
+                .or_else(|error| upload_err(target, format!("couldn't copy binary: {error}")))?;
+        }
+        Builder::Go => build_go(options, target, architecture, &bootstrap).await?,
+    }
+
+    // The zip is built from the binary, but errors should still refer to the original source
+    let src = std::mem::replace(&mut target.src, Src::Local(bootstrap));
+    let zip = package_zip(options, target).await;
+    target.src = src;
+    zip
+}
+
+/// Build a Cargo package, returning the path to the compiled binary.
+///
+/// If the package has multiple binaries, `Metadata.Cloudformatious.Binary` selects one.
+async fn build_rust(
+    options: &Options<'_>,
+    target: &Target<'_>,
+    architecture: Architecture,
+) -> Result<PathBuf, Error> {
+    let binary = match target.metadata.get("Binary") {
+        None => None,
+        Some(binary) => match binary.as_str() {
+            Some(binary) => Some(binary.to_string()),
+            None => {
+                return upload_err(
+                    target,
+                    format!("`{METADATA_KEY}.Binary` metadata must be a string"),
+                )
+            }
+        },
+    };
+
+    let Src::Local(src) = &target.src;
+    let mut command = Command::new("cargo");
+    command
+        .arg("build")
+        .arg("--release")
+        .arg("--message-format=json-render-diagnostics")
+        .args(["--target", architecture.rust_target()])
+        .arg("--manifest-path")
+        .arg(src.join("Cargo.toml"));
+    if let Some(binary) = &binary {
+        command.args(["--bin", binary]);
+    }
+    let messages = run_command(options, target, "`cargo build`", command, true).await?;
+
+    // Compiler artifacts with an `executable` are the binaries that were built
+    let executables: Vec<_> = messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|message| message["reason"] == "compiler-artifact")
+        .filter_map(|message| message["executable"].as_str().map(PathBuf::from))
+        .collect();
+    match executables.as_slice() {
+        [executable] => Ok(executable.clone()),
+        [] => upload_err(target, "`cargo build` didn't produce a binary"),
+        _ => upload_err(
+            target,
+            format!(
+                "`cargo build` produced multiple binaries, select one with `{METADATA_KEY}.Binary`"
+            ),
+        ),
+    }
+}
+
+/// Build a Go module into `output`.
+async fn build_go(
+    options: &Options<'_>,
+    target: &Target<'_>,
+    architecture: Architecture,
+    output: &std::path::Path,
+) -> Result<(), Error> {
+    let Src::Local(src) = &target.src;
+    let mut command = Command::new("go");
+    command
+        .current_dir(src)
+        .env("GOOS", "linux")
+        .env("GOARCH", architecture.goarch())
+        .env("CGO_ENABLED", "0")
+        .args(["build", "-trimpath", "-tags", "lambda.norpc", "-o"])
+        .arg(output)
+        .arg(".");
+    run_command(options, target, "`go build`", command, false).await?;
+    Ok(())
+}
@@ -1,60 +1,594 @@
 use std::{
-    convert::TryInto,
-    path::Path,
-    sync::{Arc, Mutex},
+    convert::{TryFrom, TryInto},
+    fmt,
+    io::SeekFrom,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use aws_sdk_s3::primitives::ByteStream;
+use aws_config::SdkConfig;
+use aws_sdk_s3::{
+    error::SdkError,
+    primitives::ByteStream,
+    types::{
+        AbortIncompleteMultipartUpload, BucketLifecycleConfiguration, BucketLocationConstraint,
+        CompletedMultipartUpload, CompletedPart, CreateBucketConfiguration, Delete,
+        ExpirationStatus, LifecycleRule, LifecycleRuleFilter, ObjectCannedAcl, ObjectIdentifier,
+        PublicAccessBlockConfiguration, ServerSideEncryption, ServerSideEncryptionByDefault,
+        ServerSideEncryptionConfiguration, ServerSideEncryptionRule,
+    },
+};
 use aws_types::region::Region;
-use futures_util::{StreamExt, TryStreamExt};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
 use tokio::{
     fs::File,
-    io::{AsyncSeekExt, BufReader},
+    io::{AsyncReadExt, AsyncSeekExt, BufReader},
 };
 use tokio_util::codec::{BytesCodec, FramedRead};
 
-use crate::{client::get_client, Error};
+use crate::{client::get_config, Error};
+
+/// The largest object that can be uploaded with a single `put_object`.
+const MAX_PUT_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// The value of `--package-bucket` that selects the managed bucket.
+pub const MANAGED_BUCKET: &str = "auto";
+
+/// The maximum number of parts in a multipart upload.
+const MAX_PARTS: u64 = 10_000;
+
+/// The number of parts of a multipart upload to send at once.
+const PART_CONCURRENCY: usize = 4;
+
+/// The number of times to try uploading each part of a multipart upload.
+const PART_ATTEMPTS: u32 = 3;
+
+/// The default size above which files are uploaded in parts.
+pub const DEFAULT_MULTIPART_THRESHOLD: u64 = 100 * 1024 * 1024;
+
+/// The default size of each part for multipart uploads.
+pub const DEFAULT_MULTIPART_PART_SIZE: u64 = 16 * 1024 * 1024;
 
 pub struct Client {
     inner: aws_sdk_s3::Client,
+    config: SdkConfig,
 }
 
 impl Client {
     pub async fn new(region: Option<Region>, no_input: bool) -> Result<Self, Error> {
-        let inner = get_client(aws_sdk_s3::Client::new, region, no_input).await?;
-        Ok(Self { inner })
+        let config = get_config(region, no_input).await?;
+        let inner = aws_sdk_s3::Client::new(&config);
+        Ok(Self { inner, config })
+    }
+
+    /// The region requests are made in, if any.
+    pub fn region(&self) -> Option<&Region> {
+        self.config.region()
+    }
+
+    /// Find or create the managed bucket for the current account and region.
+    ///
+    /// The bucket is named `cloudformatious-<account>-<region>`. If it doesn't exist, it's created
+    /// (depending on `create`) with default encryption, public access blocked, and a lifecycle rule
+    /// to abort incomplete multipart uploads. Packages are never expired, since they may still be
+    /// needed by nested stacks or rollbacks; use `gc-packages` to remove unused packages.
+    pub async fn managed_bucket(&self, create: CreateBucket) -> Result<String, Error> {
+        let (bucket, region) = self.managed_bucket_name().await?;
+        if self.bucket_exists(&bucket).await? {
+            return Ok(bucket);
+        }
+
+        let confirmed = match create {
+            CreateBucket::Yes => true,
+            CreateBucket::Prompt => {
+                confirm(&format!("Create managed package bucket {bucket}?")).await
+            }
+            CreateBucket::NoPrompt => {
+                return Err(Error::other(format!(
+                    "managed package bucket {bucket} doesn't exist, and confirmation can't be \
+                     requested; use `--yes` to create it"
+                )))
+            }
+        };
+        if !confirmed {
+            return Err(Error::other(format!(
+                "managed package bucket {bucket} doesn't exist"
+            )));
+        }
+        self.create_managed_bucket(&bucket, &region)
+            .await
+            .map_err(|error| {
+                Error::other(format!(
+                    "an error occurred when creating managed package bucket {bucket}: {error}"
+                ))
+            })?;
+
+        Ok(bucket)
+    }
+
+    /// Find the managed bucket for the current account and region, failing if it doesn't exist.
+    pub async fn existing_managed_bucket(&self) -> Result<String, Error> {
+        let (bucket, _) = self.managed_bucket_name().await?;
+        if !self.bucket_exists(&bucket).await? {
+            return Err(Error::other(format!(
+                "managed package bucket {bucket} doesn't exist"
+            )));
+        }
+        Ok(bucket)
+    }
+
+    /// The name of the managed bucket for the current account and region, and the region.
+    async fn managed_bucket_name(&self) -> Result<(String, String), Error> {
+        let Some(region) = self.config.region().map(Region::to_string) else {
+            return Err(Error::other(
+                "a region is required to find the managed package bucket",
+            ));
+        };
+        let identity = aws_sdk_sts::Client::new(&self.config)
+            .get_caller_identity()
+            .send()
+            .await
+            .map_err(|error| {
+                Error::other(format!(
+                    "couldn't determine the account for the managed package bucket: {error}"
+                ))
+            })?;
+        let account = identity
+            .account
+            .expect("BUG: caller identity has no account");
+        let bucket = format!("cloudformatious-{account}-{region}");
+        Ok((bucket, region))
+    }
+
+    async fn bucket_exists(&self, bucket: &str) -> Result<bool, Error> {
+        match self.inner.head_bucket().bucket(bucket).send().await {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(err)) if err.err().is_not_found() => Ok(false),
+            Err(error) => Err(Error::other(format!(
+                "an error occurred when trying to read managed package bucket {bucket}: {error}"
+            ))),
+        }
+    }
+
+    async fn create_managed_bucket(
+        &self,
+        bucket: &str,
+        region: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // `us-east-1` is the default, and can't be given as a location constraint
+        let location = (region != "us-east-1").then(|| {
+            CreateBucketConfiguration::builder()
+                .location_constraint(BucketLocationConstraint::from(region))
+                .build()
+        });
+        self.inner
+            .create_bucket()
+            .bucket(bucket)
+            .set_create_bucket_configuration(location)
+            .send()
+            .await?;
+
+        self.inner
+            .put_public_access_block()
+            .bucket(bucket)
+            .public_access_block_configuration(
+                PublicAccessBlockConfiguration::builder()
+                    .block_public_acls(true)
+                    .block_public_policy(true)
+                    .ignore_public_acls(true)
+                    .restrict_public_buckets(true)
+                    .build(),
+            )
+            .send()
+            .await?;
+
+        self.inner
+            .put_bucket_encryption()
+            .bucket(bucket)
+            .server_side_encryption_configuration(
+                ServerSideEncryptionConfiguration::builder()
+                    .rules(
+                        ServerSideEncryptionRule::builder()
+                            .apply_server_side_encryption_by_default(
+                                ServerSideEncryptionByDefault::builder()
+                                    .sse_algorithm(ServerSideEncryption::Aes256)
+                                    .build()?,
+                            )
+                            .build(),
+                    )
+                    .build()?,
+            )
+            .send()
+            .await?;
+
+        self.inner
+            .put_bucket_lifecycle_configuration()
+            .bucket(bucket)
+            .lifecycle_configuration(
+                BucketLifecycleConfiguration::builder()
+                    .rules(
+                        LifecycleRule::builder()
+                            .id("AbortIncompleteUploads")
+                            .status(ExpirationStatus::Enabled)
+                            .filter(LifecycleRuleFilter::Prefix(String::new()))
+                            .abort_incomplete_multipart_upload(
+                                AbortIncompleteMultipartUpload::builder()
+                                    .days_after_initiation(1)
+                                    .build(),
+                            )
+                            .build()?,
+                    )
+                    .build()?,
+            )
+            .send()
+            .await?;
+
+        Ok(())
     }
 
     pub async fn upload(&self, request: UploadRequest<'_>) -> Result<UploadOutput, Error> {
-        let meta = request
-            .file
+        let content = hash_file(request.file, request.config.key_hash).await?;
+        let key = request.config.key_template.render(
+            request.prefix.unwrap_or(""),
+            request.resource,
+            &content.hash,
+            request.extension,
+        );
+        let uri = self
+            .put_if_absent(
+                request.bucket,
+                &key,
+                content.file,
+                content.md5,
+                request.config,
+                request.progress,
+            )
+            .await?;
+        Ok(UploadOutput {
+            uri,
+            key,
+            hash: content.hash,
+        })
+    }
+
+    /// Upload `file` to `key` in `bucket`, unless it's already present, returning its URI.
+    pub async fn upload_to_key(
+        &self,
+        bucket: &str,
+        key: &str,
+        file: File,
+        config: &UploadConfig,
+        progress: Option<Box<dyn Fn(UploadProgress) + Send + Sync>>,
+    ) -> Result<String, Error> {
+        let content = hash_file(file, KeyHash::Md5).await?;
+        self.put_if_absent(bucket, key, content.file, content.md5, config, progress)
+            .await
+    }
+
+    async fn put_if_absent(
+        &self,
+        bucket: &str,
+        key: &str,
+        file: File,
+        content_md5: md5::Digest,
+        config: &UploadConfig,
+        progress: Option<Box<dyn Fn(UploadProgress) + Send + Sync>>,
+    ) -> Result<String, Error> {
+        let meta = file
             .metadata()
             .await
             .map_err(|error| Error::other(format!("couldn't stat upload package: {error}",)))?;
 
-        let mut reader = FramedRead::new(BufReader::new(request.file), BytesCodec::new());
+        let (uri, exists) = self.head(bucket, key, config).await?;
+        if exists {
+            if let Some(progress) = &progress {
+                progress(UploadProgress::Skipped);
+            }
+            return Ok(uri);
+        }
 
-        let context = reader
-            .by_ref()
-            .try_fold(md5::Context::new(), |mut context, chunk| async move {
-                context.consume(&chunk);
-                Ok(context)
-            })
+        let total = meta.len();
+        if total > config.multipart_threshold || total > MAX_PUT_SIZE {
+            self.upload_multipart(bucket, key, file, total, config, progress.as_deref())
+                .await?;
+            return Ok(uri);
+        }
+
+        let mut uploaded = 0;
+        let body = hyper::Body::wrap_stream(
+            FramedRead::new(BufReader::new(file), BytesCodec::new()).inspect_ok(move |chunk| {
+                uploaded += chunk.len() as u64;
+                if let Some(progress) = &progress {
+                    progress(UploadProgress::Uploading { uploaded, total });
+                }
+            }),
+        );
+
+        self.inner
+            .put_object()
+            .body(ByteStream::from_body_0_4(body))
+            .bucket(bucket)
+            .content_length(meta.len().try_into().expect("file is insanely large"))
+            .content_md5(base64::encode(content_md5.0))
+            .key(key)
+            .set_server_side_encryption(config.server_side_encryption())
+            .set_ssekms_key_id(config.kms_key_id.clone())
+            .set_acl(config.acl.map(Acl::to_sdk))
+            .set_tagging(config.tagging())
+            .send()
+            .await
+            .map_err(|error| {
+                Error::other(format!(
+                    "an error occurred when uploading package to {key}{}: {error:#?}",
+                    denied_context(&error, config),
+                ))
+            })?;
+
+        Ok(uri)
+    }
+
+    /// Upload `file` to `key` in parts, aborting the multipart upload if any part fails.
+    async fn upload_multipart(
+        &self,
+        bucket: &str,
+        key: &str,
+        file: File,
+        total: u64,
+        config: &UploadConfig,
+        progress: Option<&(dyn Fn(UploadProgress) + Send + Sync)>,
+    ) -> Result<(), Error> {
+        let upload_id = self
+            .inner
+            .create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .set_server_side_encryption(config.server_side_encryption())
+            .set_ssekms_key_id(config.kms_key_id.clone())
+            .set_acl(config.acl.map(Acl::to_sdk))
+            .set_tagging(config.tagging())
+            .send()
             .await
-            .map_err(|error| Error::other(format!("couldn't read upload package: {error}",)))?;
-        let content_md5 = context.compute();
+            .map_err(|error| {
+                Error::other(format!(
+                    "an error occurred when starting multipart upload to {key}{}: {error:#?}",
+                    denied_context(&error, config),
+                ))
+            })?
+            .upload_id
+            .expect("BUG: multipart upload has no ID");
+
+        let parts = match self
+            .upload_parts(bucket, key, &upload_id, file, total, config, progress)
+            .await
+        {
+            Ok(parts) => parts,
+            Err(error) => {
+                // Abort so that the uploaded parts don't linger (and incur charges)
+                let _ = self
+                    .inner
+                    .abort_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(&upload_id)
+                    .send()
+                    .await;
+                return Err(error);
+            }
+        };
+
+        self.inner
+            .complete_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|error| {
+                Error::other(format!(
+                    "an error occurred when completing multipart upload to {key}{}: {error:#?}",
+                    denied_context(&error, config),
+                ))
+            })?;
+
+        Ok(())
+    }
+
+    /// Upload the parts of `file`, a few at a time, retrying each part on failure.
+    #[allow(clippy::too_many_arguments)]
+    async fn upload_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        file: File,
+        total: u64,
+        config: &UploadConfig,
+        progress: Option<&(dyn Fn(UploadProgress) + Send + Sync)>,
+    ) -> Result<Vec<CompletedPart>, Error> {
+        // S3 allows at most 10,000 parts, so large files may need bigger parts
+        let part_size = config.multipart_part_size.max(total.div_ceil(MAX_PARTS));
+        let file = tokio::sync::Mutex::new(file);
+        let uploaded = AtomicU64::new(0);
+
+        let parts = (0..total.div_ceil(part_size)).map(|index| {
+            let file = &file;
+            let uploaded = &uploaded;
+            async move {
+                let offset = index * part_size;
+                let mut part = vec![0; part_size.min(total - offset) as usize];
+                {
+                    let mut file = file.lock().await;
+                    async {
+                        file.seek(SeekFrom::Start(offset)).await?;
+                        file.read_exact(&mut part).await
+                    }
+                    .await
+                    .map_err(|error| {
+                        Error::other(format!("couldn't read upload package: {error}"))
+                    })?;
+                }
+                let part_number = i32::try_from(index + 1).expect("too many parts");
+                let content_md5 = base64::encode(md5::compute(&part).0);
+                let content_length = part.len().try_into().expect("part is insanely large");
+
+                let mut attempt = 1;
+                let output = loop {
+                    let result = self
+                        .inner
+                        .upload_part()
+                        .bucket(bucket)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .part_number(part_number)
+                        .body(ByteStream::from(part.clone()))
+                        .content_length(content_length)
+                        .content_md5(&content_md5)
+                        .send()
+                        .await;
+                    match result {
+                        Ok(output) => break output,
+                        Err(_) if attempt < PART_ATTEMPTS => {
+                            tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                            attempt += 1;
+                        }
+                        Err(error) => {
+                            return Err(Error::other(format!(
+                                "an error occurred when uploading part {part_number} of package \
+                                 to {key}{}: {error:#?}",
+                                denied_context(&error, config),
+                            )))
+                        }
+                    }
+                };
+
+                let done =
+                    uploaded.fetch_add(part.len() as u64, Ordering::Relaxed) + part.len() as u64;
+                if let Some(progress) = progress {
+                    progress(UploadProgress::Uploading {
+                        uploaded: done,
+                        total,
+                    });
+                }
+
+                Ok(CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(output.e_tag)
+                    .build())
+            }
+        });
+
+        let mut parts: Vec<_> = stream::iter(parts)
+            .buffer_unordered(PART_CONCURRENCY)
+            .try_collect()
+            .await?;
+        parts.sort_by_key(|part| part.part_number);
+        Ok(parts)
+    }
+
+    /// List the objects in `bucket` under `prefix`.
+    pub async fn list_objects(&self, bucket: &str, prefix: &str) -> Result<Vec<Object>, Error> {
+        let mut pages = self
+            .inner
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|error| {
+                Error::other(format!(
+                    "an error occurred when listing s3://{bucket}/{prefix}: {error}"
+                ))
+            })?;
+            objects.extend(
+                page.contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|object| {
+                        Some(Object {
+                            key: object.key?,
+                            last_modified: object.last_modified?.secs(),
+                        })
+                    }),
+            );
+        }
+        Ok(objects)
+    }
 
-        let key = Path::new(request.prefix.unwrap_or(""))
-            .join(format!("{:x}", content_md5))
-            .to_string_lossy()
-            .into_owned();
+    /// Delete `keys` from `bucket`.
+    pub async fn delete_objects(&self, bucket: &str, keys: &[String]) -> Result<(), Error> {
+        // At most 1,000 objects can be deleted per request
+        for keys in keys.chunks(1000) {
+            let objects = keys
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<Result<_, _>>()
+                .map_err(Error::other)?;
+            let output = self
+                .inner
+                .delete_objects()
+                .bucket(bucket)
+                .delete(
+                    Delete::builder()
+                        .set_objects(Some(objects))
+                        .quiet(true)
+                        .build()
+                        .map_err(Error::other)?,
+                )
+                .send()
+                .await
+                .map_err(|error| {
+                    Error::other(format!(
+                        "an error occurred when deleting objects from {bucket}: {error}"
+                    ))
+                })?;
+            if let Some(error) = output.errors.unwrap_or_default().into_iter().next() {
+                return Err(Error::other(format!(
+                    "couldn't delete s3://{bucket}/{}: {}",
+                    error.key.unwrap_or_default(),
+                    error.message.unwrap_or_default()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether `key` exists in `bucket`, returning its URI if so.
+    pub async fn exists(
+        &self,
+        bucket: &str,
+        key: &str,
+        config: &UploadConfig,
+    ) -> Result<Option<String>, Error> {
+        let (uri, exists) = self.head(bucket, key, config).await?;
+        Ok(exists.then_some(uri))
+    }
 
+    /// Check whether `key` exists in `bucket`, returning its URI either way.
+    async fn head(
+        &self,
+        bucket: &str,
+        key: &str,
+        config: &UploadConfig,
+    ) -> Result<(String, bool), Error> {
         let uri: Arc<Mutex<Option<String>>> = Default::default();
         let exists = self
             .inner
             .head_object()
-            .bucket(request.bucket)
-            .key(&key)
+            .bucket(bucket)
+            .key(key)
             .customize()
             .mutate_request({
                 let uri = uri.clone();
@@ -65,63 +599,372 @@ impl Client {
             .send()
             .await
             .map(|_| true)
-            .or_else({
-                let bucket = &request.bucket;
-                let key = &key;
-                move |error| match error {
-                    aws_sdk_s3::error::SdkError::ServiceError(err) if err.err().is_not_found() => {
-                        Ok(false)
-                    }
-                    error => Err(Error::other(format!(
-                        "an error occurred when trying to read s3://{bucket}/{key}: {error}",
-                    ))),
-                }
+            .or_else(move |error| match error {
+                SdkError::ServiceError(err) if err.err().is_not_found() => Ok(false),
+                error => Err(Error::other(format!(
+                    "an error occurred when trying to read s3://{bucket}/{key}{}: {error}",
+                    denied_context(&error, config),
+                ))),
             })?;
         let uri = uri
             .lock()
             .unwrap()
             .take()
             .expect("BUG: uri not set after request");
-        if exists {
-            return Ok(UploadOutput { uri, key });
+        Ok((uri, exists))
+    }
+}
+
+/// Settings for uploads, which apply to every package.
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    /// Files larger than this many bytes are uploaded in parts.
+    pub multipart_threshold: u64,
+
+    /// The size of each part, in bytes, for multipart uploads.
+    pub multipart_part_size: u64,
+
+    /// The server-side encryption to request for uploaded objects.
+    pub sse: Option<Encryption>,
+
+    /// The KMS key to encrypt uploaded objects with, implying `aws:kms` encryption if `sse` is
+    /// not set.
+    pub kms_key_id: Option<String>,
+
+    /// The canned ACL to apply to uploaded objects.
+    pub acl: Option<Acl>,
+
+    /// Tags to apply to uploaded objects.
+    pub tags: Vec<(String, String)>,
+
+    /// The hash of the content used in keys.
+    pub key_hash: KeyHash,
+
+    /// The template for keys.
+    pub key_template: KeyTemplate,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            multipart_threshold: DEFAULT_MULTIPART_THRESHOLD,
+            multipart_part_size: DEFAULT_MULTIPART_PART_SIZE,
+            sse: None,
+            kms_key_id: None,
+            acl: None,
+            tags: Vec::new(),
+            key_hash: KeyHash::default(),
+            key_template: KeyTemplate::default(),
         }
+    }
+}
 
-        let mut file = reader.into_inner().into_inner();
-        file.rewind()
-            .await
-            .map_err(|error| Error::other(format!("couldn't read upload package: {error}")))?;
+impl UploadConfig {
+    fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        match (self.sse, &self.kms_key_id) {
+            (Some(sse), _) => Some(sse.to_sdk()),
+            (None, Some(_)) => Some(ServerSideEncryption::AwsKms),
+            (None, None) => None,
+        }
+    }
 
-        let body =
-            hyper::Body::wrap_stream(FramedRead::new(BufReader::new(file), BytesCodec::new()));
+    /// Tags in the URL-encoded form expected by `put_object`.
+    fn tagging(&self) -> Option<String> {
+        if self.tags.is_empty() {
+            return None;
+        }
+        Some(
+            form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(&self.tags)
+                .finish(),
+        )
+    }
 
-        self.inner
-            .put_object()
-            .body(ByteStream::from_body_0_4(body))
-            .bucket(request.bucket)
-            .content_length(meta.len().try_into().expect("file is insanely large"))
-            .content_md5(base64::encode(content_md5.0))
-            .key(&key)
-            .send()
-            .await
-            .map_err(|error| {
-                Error::other(format!(
-                    "an error occurred when uploading package to {key}: {error:#?}",
-                ))
-            })?;
+    /// A description of the requested encryption, for error messages.
+    fn encryption_context(&self) -> String {
+        match (self.server_side_encryption(), &self.kms_key_id) {
+            (Some(sse), Some(kms_key_id)) => {
+                format!("server-side encryption `{sse}` with KMS key `{kms_key_id}`")
+            }
+            (Some(sse), None) => format!("server-side encryption `{sse}`"),
+            (None, _) => "the bucket's default encryption".to_string(),
+        }
+    }
+}
+
+/// The hash algorithm used to derive keys from content.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum KeyHash {
+    /// The hex-encoded MD5 hash of the content.
+    #[default]
+    Md5,
+
+    /// The hex-encoded SHA-256 hash of the content.
+    Sha256,
+}
+
+/// A template for keys, e.g. `{prefix}/{resource}/{hash}{ext}`.
+///
+/// Empty path segments (e.g. from an empty `{prefix}`) are removed from rendered keys.
+#[derive(Clone, Debug)]
+pub struct KeyTemplate(String);
+
+impl KeyTemplate {
+    /// Render a key for a package.
+    ///
+    /// `resource` is a logical ID, or a type for packages that don't belong to a resource (e.g.
+    /// `AWS::Include`), in which case `::` is replaced with `-`.
+    pub fn render(&self, prefix: &str, resource: &str, hash: &str, ext: &str) -> String {
+        self.0
+            .replace("{prefix}", prefix)
+            .replace("{resource}", &resource.replace("::", "-"))
+            .replace("{hash}", hash)
+            .replace("{ext}", ext)
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+impl Default for KeyTemplate {
+    fn default() -> Self {
+        Self("{prefix}/{hash}".to_string())
+    }
+}
 
-        Ok(UploadOutput { uri, key })
+impl fmt::Display for KeyTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for KeyTemplate {
+    type Err = InvalidKeyTemplate;
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        // Keys must be content-addressed, so that unchanged packages aren't uploaded again
+        if !template.contains("{hash}") {
+            return Err(InvalidKeyTemplate(template.to_string()));
+        }
+        Ok(Self(template.to_string()))
     }
 }
 
 #[derive(Debug)]
+pub struct InvalidKeyTemplate(String);
+
+impl fmt::Display for InvalidKeyTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid key template `{}`, must contain `{{hash}}`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidKeyTemplate {}
+
+/// Server-side encryption for uploaded packages.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Encryption {
+    /// Encrypt with S3 managed keys.
+    #[value(name = "AES256")]
+    Aes256,
+
+    /// Encrypt with a KMS key.
+    #[value(name = "aws:kms")]
+    AwsKms,
+
+    /// Encrypt with a KMS key, using dual-layer encryption.
+    #[value(name = "aws:kms:dsse")]
+    AwsKmsDsse,
+}
+
+impl Encryption {
+    fn to_sdk(self) -> ServerSideEncryption {
+        match self {
+            Self::Aes256 => ServerSideEncryption::Aes256,
+            Self::AwsKms => ServerSideEncryption::AwsKms,
+            Self::AwsKmsDsse => ServerSideEncryption::AwsKmsDsse,
+        }
+    }
+}
+
+/// A canned ACL for uploaded packages.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Acl {
+    Private,
+    PublicRead,
+    PublicReadWrite,
+    AuthenticatedRead,
+    AwsExecRead,
+    BucketOwnerRead,
+    BucketOwnerFullControl,
+}
+
+impl Acl {
+    fn to_sdk(self) -> ObjectCannedAcl {
+        match self {
+            Self::Private => ObjectCannedAcl::Private,
+            Self::PublicRead => ObjectCannedAcl::PublicRead,
+            Self::PublicReadWrite => ObjectCannedAcl::PublicReadWrite,
+            Self::AuthenticatedRead => ObjectCannedAcl::AuthenticatedRead,
+            Self::AwsExecRead => ObjectCannedAcl::AwsExecRead,
+            Self::BucketOwnerRead => ObjectCannedAcl::BucketOwnerRead,
+            Self::BucketOwnerFullControl => ObjectCannedAcl::BucketOwnerFullControl,
+        }
+    }
+}
+
+/// Whether to create the managed bucket if it doesn't exist.
+#[derive(Clone, Copy, Debug)]
+pub enum CreateBucket {
+    /// Create the bucket without asking.
+    Yes,
+
+    /// Ask for confirmation on STDIN.
+    Prompt,
+
+    /// Fail, since confirmation can't be requested (e.g. STDIN isn't a terminal).
+    NoPrompt,
+}
+
+/// Ask the user to confirm `prompt` on STDERR, reading a `y` or `n` answer from STDIN.
+async fn confirm(prompt: &str) -> bool {
+    eprint!("{prompt} [y/N] ");
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).map(|_| answer)
+    })
+    .await
+    .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()));
+    matches!(answer.as_deref().map(str::trim), Ok("y" | "Y" | "yes"))
+}
+
+/// If `error` is an access denied error, describe the requested encryption.
+///
+/// Denials are often caused by a bucket policy requiring particular encryption, or missing
+/// permissions on a KMS key, neither of which is clear from the error itself.
+fn denied_context<E>(error: &SdkError<E>, config: &UploadConfig) -> String {
+    match error.raw_response() {
+        Some(response) if response.status().as_u16() == 403 => {
+            format!(" (requested {})", config.encryption_context())
+        }
+        _ => String::new(),
+    }
+}
+
 pub struct UploadRequest<'a> {
     pub bucket: &'a str,
     pub prefix: Option<&'a str>,
     pub file: File,
+    pub config: &'a UploadConfig,
+
+    /// The logical ID of the resource the package is for, for `{resource}` in key templates.
+    pub resource: &'a str,
+
+    /// The extension of the package (e.g. `.zip`), for `{ext}` in key templates.
+    pub extension: &'a str,
+
+    /// A callback to report the progress of the upload.
+    pub progress: Option<Box<dyn Fn(UploadProgress) + Send + Sync>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum UploadProgress {
+    /// The content was already present in the bucket, so wasn't uploaded.
+    Skipped,
+
+    /// Some of the content has been sent.
+    Uploading { uploaded: u64, total: u64 },
+}
+
+/// A file, along with its hashes.
+pub struct HashedFile {
+    /// The file, rewound to the start.
+    pub file: File,
+
+    /// The MD5 hash of the content, used to verify uploads.
+    pub md5: md5::Digest,
+
+    /// The hex-encoded hash of the content used in keys.
+    pub hash: String,
+}
+
+/// Hash the content of `file`, with MD5 and `key_hash`.
+pub async fn hash_file(file: File, key_hash: KeyHash) -> Result<HashedFile, Error> {
+    let mut reader = FramedRead::new(BufReader::new(file), BytesCodec::new());
+
+    let (md5, sha256) = reader
+        .by_ref()
+        .try_fold(
+            (md5::Context::new(), Sha256::new()),
+            |(mut md5, mut sha256), chunk| async move {
+                md5.consume(&chunk);
+                if let KeyHash::Sha256 = key_hash {
+                    sha256.update(&chunk);
+                }
+                Ok((md5, sha256))
+            },
+        )
+        .await
+        .map_err(|error| Error::other(format!("couldn't read upload package: {error}",)))?;
+    let md5 = md5.compute();
+
+    let hash = match key_hash {
+        KeyHash::Md5 => format!("{:x}", md5),
+        KeyHash::Sha256 => format!("{:x}", sha256.finalize()),
+    };
+
+    let mut file = reader.into_inner().into_inner();
+    file.rewind()
+        .await
+        .map_err(|error| Error::other(format!("couldn't read upload package: {error}")))?;
+
+    Ok(HashedFile { file, md5, hash })
+}
+
+/// An object in a bucket.
+#[derive(Debug)]
+pub struct Object {
+    pub key: String,
+
+    /// The time the object was last modified, in seconds since the Unix epoch.
+    pub last_modified: i64,
 }
 
 #[derive(Debug)]
 pub struct UploadOutput {
     pub uri: String,
     pub key: String,
+
+    /// The hex-encoded hash of the uploaded content, as used in the key.
+    pub hash: String,
+}
+
+#[test]
+fn test_render_key() {
+    let template = KeyTemplate::default();
+    assert_eq!(template.render("", "Function", "abc", ".zip"), "abc");
+    assert_eq!(
+        template.render("a/b/", "Function", "abc", ".zip"),
+        "a/b/abc"
+    );
+
+    let template: KeyTemplate = "{prefix}/{resource}/{hash}{ext}".parse().unwrap();
+    assert_eq!(
+        template.render("", "Function", "abc", ".zip"),
+        "Function/abc.zip"
+    );
+    assert_eq!(
+        template.render("pkg", "Function", "abc", ""),
+        "pkg/Function/abc"
+    );
+    assert_eq!(
+        template.render("pkg", "AWS::Include", "abc", ".yaml"),
+        "pkg/AWS-Include/abc.yaml"
+    );
+
+    assert!("{prefix}/{resource}".parse::<KeyTemplate>().is_err());
 }
@@ -10,7 +10,10 @@ use tokio::{
     io::{self, AsyncReadExt},
 };
 
-use crate::Error;
+use crate::{package::local_path, Error};
+
+/// Errors that can occur when inlining includes, which must be sent between threads.
+type IncludeError = Box<dyn std::error::Error + Send + Sync>;
 
 pub struct Template {
     path: Option<PathBuf>,
@@ -21,6 +24,7 @@ pub struct ResourceMut<'t> {
     resource_id: &'t str,
     resource_type: &'t mut str,
     properties: &'t mut YamlValue,
+    metadata: Option<&'t YamlValue>,
 }
 
 impl<'t> ResourceMut<'t> {
@@ -31,6 +35,10 @@ impl<'t> ResourceMut<'t> {
     pub fn resource_type(&self) -> &str {
         &*self.resource_type
     }
+
+    pub fn metadata(&self) -> Option<&YamlValue> {
+        self.metadata
+    }
 }
 
 impl Template {
@@ -49,7 +57,7 @@ impl Template {
             if !meta.is_file() {
                 return Err(Error::other(ReadError::new(
                     path.as_path(),
-                    io::Error::new(io::ErrorKind::Other, "not a file"),
+                    io::Error::other("not a file"),
                 )));
             }
 
@@ -68,7 +76,7 @@ impl Template {
         Ok(Self { path, content })
     }
 
-    pub fn source(&self) -> Source {
+    pub fn source(&self) -> Source<'_> {
         self.path
             .as_deref()
             .map_or_else(|| Source::Stdin, Source::from)
@@ -84,18 +92,21 @@ impl Template {
             .filter_map(|(key, val)| {
                 let resource_id = key.as_str()?;
                 let resource = val.as_mapping_mut()?;
-                let (resource_type, properties) = resource.iter_mut().fold(
-                    (None, None),
-                    |(resource_type, properties), (key, value)| {
+                let (resource_type, properties, metadata) = resource.iter_mut().fold(
+                    (None, None, None),
+                    |(resource_type, properties, metadata), (key, value)| {
                         if let Some("Type") = key.as_str() {
                             if let YamlValue::String(resource_type) = value {
-                                return (Some(resource_type), properties);
+                                return (Some(resource_type), properties, metadata);
                             }
                         }
                         if let Some("Properties") = key.as_str() {
-                            return (resource_type, Some(value));
+                            return (resource_type, Some(value), metadata);
                         }
-                        (resource_type, properties)
+                        if let Some("Metadata") = key.as_str() {
+                            return (resource_type, properties, Some(&*value));
+                        }
+                        (resource_type, properties, metadata)
                     },
                 );
                 let resource_type = resource_type?;
@@ -104,9 +115,248 @@ impl Template {
                     resource_id,
                     resource_type,
                     properties,
+                    metadata,
                 })
             })
     }
+
+    /// Replace `AWS::Include` transforms that have local locations with the content they refer to.
+    ///
+    /// Included snippets are themselves processed recursively, relative to their own location. An
+    /// error is returned if a snippet (directly or indirectly) includes itself.
+    pub async fn inline_includes(&mut self) -> Result<(), Error> {
+        let mut content = std::mem::take(&mut self.content);
+        let path = self.path.clone();
+
+        let content = tokio::task::spawn_blocking(move || {
+            let mut chain = Vec::new();
+            if let Some(path) = &path {
+                chain.push(
+                    path.canonicalize()
+                        .map_err(ReadError::for_source(path.as_path()))?,
+                );
+            }
+            let dir = path
+                .as_deref()
+                .and_then(Path::parent)
+                .unwrap_or_else(|| Path::new(""));
+
+            inline_top_level_includes(&mut content, dir, &mut chain)?;
+            inline_includes(&mut content, dir, &mut chain)?;
+            Ok::<_, IncludeError>(content)
+        })
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+        .map_err(|error| Error::Other(error))?;
+
+        self.content = content;
+        Ok(())
+    }
+
+    /// Iterate over the `Location` parameters of every `AWS::Include` transform in the template.
+    ///
+    /// This covers `Fn::Transform` (including the `!Transform` short form) anywhere in the
+    /// template, as well as the top-level `Transform` section.
+    pub fn include_locations_mut(&mut self) -> impl Iterator<Item = &mut YamlValue> {
+        let mut locations = Vec::new();
+        collect_include_locations(&mut self.content, &mut locations);
+        locations.into_iter()
+    }
+}
+
+/// Inline any local includes in the top-level `Transform` section of a template.
+fn inline_top_level_includes(
+    content: &mut YamlValue,
+    dir: &Path,
+    chain: &mut Vec<PathBuf>,
+) -> Result<(), IncludeError> {
+    let Some(template) = content.as_mapping_mut() else {
+        return Ok(());
+    };
+    let Some(transform) = template.get_mut("Transform") else {
+        return Ok(());
+    };
+
+    let mut paths = Vec::new();
+    let remove = if let YamlValue::Sequence(transforms) = transform {
+        transforms.retain(|transform| {
+            let path = local_include_path(transform, dir);
+            let retain = path.is_none();
+            paths.extend(path);
+            retain
+        });
+        transforms.is_empty()
+    } else {
+        paths.extend(local_include_path(transform, dir));
+        !paths.is_empty()
+    };
+    if remove {
+        template.remove("Transform");
+    }
+
+    for path in paths {
+        let snippet = load_include(&path, chain)?;
+        merge_include(template, snippet, &path)?;
+    }
+
+    Ok(())
+}
+
+fn inline_includes(
+    value: &mut YamlValue,
+    dir: &Path,
+    chain: &mut Vec<PathBuf>,
+) -> Result<(), IncludeError> {
+    match value {
+        YamlValue::Mapping(mapping) => {
+            if let Some(path) = mapping
+                .get("Fn::Transform")
+                .and_then(|transform| local_include_path(transform, dir))
+            {
+                mapping.remove("Fn::Transform");
+                let snippet = load_include(&path, chain)?;
+                if mapping.is_empty() {
+                    *value = snippet;
+                    return Ok(());
+                }
+                merge_include(mapping, snippet, &path)?;
+            }
+
+            for (_, value) in mapping.iter_mut() {
+                inline_includes(value, dir, chain)?;
+            }
+        }
+        YamlValue::Sequence(sequence) => {
+            for value in sequence {
+                inline_includes(value, dir, chain)?;
+            }
+        }
+        YamlValue::Tagged(tagged) => {
+            if tagged.tag == "Transform" {
+                if let Some(path) = local_include_path(&tagged.value, dir) {
+                    *value = load_include(&path, chain)?;
+                    return Ok(());
+                }
+            }
+            inline_includes(&mut tagged.value, dir, chain)?;
+        }
+        YamlValue::Null | YamlValue::Bool(_) | YamlValue::Number(_) | YamlValue::String(_) => {}
+    }
+    Ok(())
+}
+
+/// The local path referenced by `transform`, if it's an `AWS::Include` with a local location.
+fn local_include_path(transform: &YamlValue, dir: &Path) -> Option<PathBuf> {
+    if transform.get("Name").and_then(YamlValue::as_str) != Some("AWS::Include") {
+        return None;
+    }
+    let location = transform.get("Parameters")?.get("Location")?.as_str()?;
+    local_path(dir, location)
+}
+
+/// Load the snippet at `path` and inline any includes it contains.
+fn load_include(path: &Path, chain: &mut Vec<PathBuf>) -> Result<YamlValue, IncludeError> {
+    let canonical_path = path.canonicalize().map_err(ReadError::for_source(path))?;
+    if chain.contains(&canonical_path) {
+        chain.push(canonical_path);
+        return Err(IncludeCycleError {
+            chain: std::mem::take(chain),
+        }
+        .into());
+    }
+
+    let yaml = std::fs::read(path).map_err(ReadError::for_source(path))?;
+    let mut content = serde_yaml::from_slice(&yaml).map_err(ParseError::for_source(path))?;
+
+    chain.push(canonical_path);
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    inline_includes(&mut content, dir, chain)?;
+    chain.pop();
+
+    Ok(content)
+}
+
+/// Merge the entries of an included `snippet` into `mapping`.
+///
+/// It's an error for the snippet to contain a key that's already present in `mapping`.
+fn merge_include(
+    mapping: &mut serde_yaml::Mapping,
+    snippet: YamlValue,
+    path: &Path,
+) -> Result<(), IncludeError> {
+    match snippet {
+        YamlValue::Mapping(snippet) => {
+            if let Some(key) = snippet.keys().find(|key| mapping.contains_key(*key)) {
+                return Err(format!(
+                    "included snippet `{}` conflicts with sibling key `{}`",
+                    path.display(),
+                    serde_yaml::to_string(key)
+                        .map(|key| key.trim_end().to_string())
+                        .unwrap_or_default()
+                )
+                .into());
+            }
+            mapping.extend(snippet);
+            Ok(())
+        }
+        YamlValue::Null => Ok(()),
+        _ => Err(format!(
+            "included snippet `{}` must be a mapping to be merged with sibling keys",
+            path.display()
+        )
+        .into()),
+    }
+}
+
+fn collect_include_locations<'t>(value: &'t mut YamlValue, locations: &mut Vec<&'t mut YamlValue>) {
+    match value {
+        YamlValue::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                if let Some("Fn::Transform" | "Transform") = key.as_str() {
+                    collect_transform_include_locations(value, locations);
+                } else {
+                    collect_include_locations(value, locations);
+                }
+            }
+        }
+        YamlValue::Sequence(sequence) => {
+            for value in sequence {
+                collect_include_locations(value, locations);
+            }
+        }
+        YamlValue::Tagged(tagged) => {
+            if tagged.tag == "Transform" {
+                collect_transform_include_locations(&mut tagged.value, locations);
+            } else {
+                collect_include_locations(&mut tagged.value, locations);
+            }
+        }
+        YamlValue::Null | YamlValue::Bool(_) | YamlValue::Number(_) | YamlValue::String(_) => {}
+    }
+}
+
+fn collect_transform_include_locations<'t>(
+    transform: &'t mut YamlValue,
+    locations: &mut Vec<&'t mut YamlValue>,
+) {
+    // The top-level `Transform` section may be a list of transforms
+    if let YamlValue::Sequence(transforms) = transform {
+        for transform in transforms {
+            collect_transform_include_locations(transform, locations);
+        }
+        return;
+    }
+
+    if transform.get("Name").and_then(YamlValue::as_str) != Some("AWS::Include") {
+        collect_include_locations(transform, locations);
+        return;
+    }
+    if let Some(location) = transform
+        .get_mut("Parameters")
+        .and_then(|parameters| parameters.get_mut("Location"))
+    {
+        locations.push(location);
+    }
 }
 
 impl fmt::Display for Template {
@@ -193,6 +443,26 @@ impl From<ReadError> for Error {
     }
 }
 
+#[derive(Debug)]
+pub struct IncludeCycleError {
+    chain: Vec<PathBuf>,
+}
+
+impl fmt::Display for IncludeCycleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "include cycle detected: ")?;
+        for (index, path) in self.chain.iter().enumerate() {
+            if index > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for IncludeCycleError {}
+
 #[derive(Debug)]
 pub struct ParseError {
     template_source: Source<'static>,
@@ -231,3 +501,65 @@ impl From<ParseError> for Error {
         Self::other(error)
     }
 }
+
+#[tokio::test]
+async fn test_include_locations_mut() {
+    let mut template = Template::open(PathBuf::from("test/fixtures/include.yaml"))
+        .await
+        .unwrap();
+    let locations: Vec<_> = template
+        .include_locations_mut()
+        .map(|location| location.as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        locations,
+        [
+            "./snippet.yaml",
+            "./snippet.yaml",
+            "s3://bucket/snippet.yaml",
+            "snippet.yaml",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_inline_includes() {
+    let mut template = Template::open(PathBuf::from("test/fixtures/include.yaml"))
+        .await
+        .unwrap();
+    template.inline_includes().await.unwrap();
+
+    let expected: YamlValue = serde_yaml::from_str(
+        r#"
+        Transform:
+          - AWS::Serverless-2016-10-31
+        Resources:
+          Bucket:
+            Type: AWS::S3::Bucket
+          Queue:
+            Type: AWS::SQS::Queue
+            Properties:
+              Fn::Transform:
+                Name: AWS::Include
+                Parameters:
+                  Location: s3://bucket/snippet.yaml
+          Topic:
+            Type: AWS::SNS::Topic
+            Properties:
+              Bucket:
+                Type: AWS::S3::Bucket
+        Bucket:
+          Type: AWS::S3::Bucket
+        "#,
+    )
+    .unwrap();
+    assert_eq!(template.content, expected);
+
+    let mut template = Template::open(PathBuf::from("test/fixtures/cycle-a.yaml"))
+        .await
+        .unwrap();
+    let error = template.inline_includes().await.unwrap_err().to_string();
+    assert!(error.starts_with("include cycle detected: "), "{}", error);
+    assert!(error.ends_with("cycle-a.yaml"), "{}", error);
+    assert_eq!(error.matches(" -> ").count(), 2, "{}", error);
+}